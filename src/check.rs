@@ -0,0 +1,342 @@
+//! Linting a config before it is deployed, for problems that would either
+//! stop the daemon from starting or make it cool the Pi poorly.
+
+use crate::config::{Config, FanConfig, RawCurve};
+use crate::controller;
+use crate::curve::Curve;
+use crate::fan::FanMode;
+use std::fmt;
+
+// The SoC starts throttling somewhere between 80 and 85°C
+pub const THROTTLE_TEMP: f32 = 80.0;
+
+/// Something wrong with the part of the config at `location`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub location: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(location: &str, message: String) -> Self {
+        Problem {
+            location: location.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Parses the config in `text` and returns every problem found with it.
+pub fn check(text: &str) -> Vec<Problem> {
+    // Unknown keys are rejected while parsing
+    match Config::parse(text) {
+        Ok(config) => check_config(&config),
+        Err(err) => vec![Problem::new("config", err.to_string())],
+    }
+}
+
+/// Returns every problem found with an already parsed config, including
+/// anything that would stop the daemon from starting with it.
+pub fn check_config(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if let Err(message) = controller::check_settings(config) {
+        problems.push(Problem::new("settings", message));
+    }
+
+    let fan_configs = config.fan_configs();
+    for (i, fan_config) in fan_configs.iter().enumerate() {
+        let name = fan_config.name(i);
+        if let Err(message) = controller::check_fan(config, &fan_configs, i) {
+            problems.push(Problem::new(&name, message));
+        }
+        check_reference(&name, &fan_config.curve, config, &mut problems);
+    }
+    for (i, sensor_config) in config.sensors.iter().enumerate() {
+        let name = controller::sensor_name(config, i);
+        if let Err(message) = controller::check_sensor(sensor_config) {
+            problems.push(Problem::new(&name, message));
+        }
+        check_reference(&name, &sensor_config.curve, config, &mut problems);
+    }
+
+    let mut names: Vec<&String> = config.curves.keys().collect();
+    names.sort();
+    let curves = [(None, &config.fan_curve)].into_iter().chain(
+        names
+            .into_iter()
+            .map(|name| (Some(name.as_str()), &config.curves[name])),
+    );
+    for (name, raw_curve) in curves {
+        let location = match name {
+            Some(name) => format!("curves.{}", name),
+            None => String::from("fan_curve"),
+        };
+        let usage = usage(config, &fan_configs, name);
+        check_curve(&location, raw_curve, usage, &mut problems);
+    }
+    problems
+}
+
+fn check_reference(
+    location: &str,
+    curve: &Option<String>,
+    config: &Config,
+    problems: &mut Vec<Problem>,
+) {
+    if let Some(curve) = curve {
+        if !config.curves.contains_key(curve) {
+            problems.push(Problem::new(location, format!("unknown curve {:?}", curve)));
+        }
+    }
+}
+
+// What a curve is used for, which decides what it is checked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Usage {
+    Built, // by nothing or only fans that ignore it, so it just has to be valid
+    Duty,
+    Rpm, // by a fan in `rpm` mode, so it gives RPM rather than duty
+}
+
+// How the curve called `name`, or `fan_curve` for `None`, is used
+fn usage(config: &Config, fan_configs: &[FanConfig], name: Option<&str>) -> Usage {
+    let by_sensor = name.is_some()
+        && config
+            .sensors
+            .iter()
+            .any(|sensor| sensor.curve.as_deref() == name);
+    let fans = || {
+        fan_configs
+            .iter()
+            .filter(move |fan| fan.curve.as_deref() == name)
+    };
+    if fans().any(|fan| fan.mode == FanMode::Rpm) {
+        Usage::Rpm
+    } else if by_sensor || fans().any(|fan| fan.mode == FanMode::Duty && fan.on_temp.is_none()) {
+        Usage::Duty
+    } else {
+        Usage::Built
+    }
+}
+
+fn check_curve(location: &str, raw_curve: &RawCurve, usage: Usage, problems: &mut Vec<Problem>) {
+    let curve = match Curve::try_from(raw_curve) {
+        Ok(curve) => curve,
+        Err(err) => {
+            problems.push(Problem::new(location, err.to_string()));
+            return;
+        }
+    };
+    if usage == Usage::Built {
+        return;
+    }
+    let points = curve.points();
+    let mut problem = |message| problems.push(Problem::new(location, message));
+
+    if points.len() < 2 {
+        problem(String::from("needs at least two points"));
+    }
+    for pair in points.windows(2) {
+        let ((temp1, speed1), (temp2, speed2)) = (pair[0], pair[1]);
+        if speed2 < speed1 {
+            problem(format!(
+                "speed drops from {} at {}°C to {} at {}°C",
+                speed1, temp1, speed2, temp2
+            ));
+        }
+    }
+    if usage == Usage::Rpm {
+        return;
+    }
+
+    for &(temp, speed) in points {
+        if !(0.0..=100.0).contains(&speed) {
+            problem(format!("duty {}% at {}°C is outside 0-100%", speed, temp));
+        }
+    }
+    let full_speed = points
+        .iter()
+        .any(|&(temp, speed)| temp <= THROTTLE_TEMP && speed >= 100.0);
+    if !full_speed {
+        problem(format!(
+            "never reaches 100% below {}°C, where the SoC starts throttling",
+            THROTTLE_TEMP
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    fn messages(config: &str) -> Vec<String> {
+        let config = format!("[settings]\nupdate_rate = 1.0\n{}", config);
+        check(&config)
+            .into_iter()
+            .map(|problem| problem.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn valid_config() {
+        assert!(messages("[fan_curve]\nraw_curve = [[40, 0], [70, 100]]").is_empty());
+    }
+
+    #[test]
+    fn curve_problems() {
+        assert_eq!(
+            messages("[fan_curve]\nraw_curve = [[40, 0], [60, 110], [85, 90]]"),
+            vec![
+                "fan_curve: speed drops from 110 at 60°C to 90 at 85°C",
+                "fan_curve: duty 110% at 60°C is outside 0-100%",
+            ]
+        );
+        assert_eq!(
+            messages("[fan_curve]\nraw_curve = [[40, 50]]"),
+            vec![
+                "fan_curve: needs at least two points",
+                "fan_curve: never reaches 100% below 80°C, where the SoC starts throttling",
+            ]
+        );
+        assert_eq!(
+            messages("[fan_curve]\nraw_curve = [[60, 0], [40, 100]]"),
+            vec!["fan_curve: point at 40°C comes after 60°C, temperatures have to increase"]
+        );
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let problems = messages("[fan_curve]\nraw_curve = [[40, 0], [70, 100]]\nspeed = 3");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("config: unknown field `speed`"));
+    }
+
+    #[test]
+    fn rpm_curves() {
+        let config = r#"
+            [fan_curve]
+            raw_curve = [[40, 0], [70, 100]]
+
+            [curves.rpm]
+            raw_curve = [[40, 1000], [70, 3000]]
+
+            [[fans]]
+            mode = "rpm"
+            curve = "rpm"
+            tach = { pin = 24 }
+
+            [[fans]]
+            name = "exhaust"
+            channel = "pwm1"
+            curve = "missing"
+        "#;
+        assert_eq!(messages(config), vec!["exhaust: unknown curve \"missing\""]);
+    }
+
+    #[test]
+    fn fan_problems() {
+        let config = r#"
+            [fan_curve]
+            raw_curve = [[40, 0], [70, 100]]
+
+            [[sensors]]
+            name = "soc"
+            thermal_zone = "cpu-thermal"
+
+            [[fans]]
+            name = "rpm"
+            mode = "rpm"
+
+            [[fans]]
+            name = "pid"
+            channel = "pwm1"
+            mode = "pid"
+            on_temp = 60
+
+            [[fans]]
+            type = "software-pwm"
+            pin = 17
+            mode = "pid"
+            sensors = ["nvme"]
+            pid = { setpoint = 50 }
+
+            [[fans]]
+            type = "software-pwm"
+            pin = 27
+            mode = "pid"
+            max_rise = 0
+        "#;
+        assert_eq!(
+            messages(config),
+            vec![
+                "rpm: the `rpm` mode needs a `tach`",
+                "pid: `on_temp` only works in `duty` mode",
+                "fan 2: unknown sensor \"nvme\"",
+                "fan 3: the `pid` mode needs a `pid.setpoint`",
+            ]
+        );
+
+        let config = "[fan_curve]\nraw_curve = [[40, 0], [70, 100]]";
+        let problems: Vec<String> = check(&format!("[settings]\nupdate_rate = 0\n{}", config))
+            .into_iter()
+            .map(|problem| problem.to_string())
+            .collect();
+        assert_eq!(problems, vec!["settings: `update_rate` has to be positive"]);
+    }
+
+    #[test]
+    fn unused_curves() {
+        // Neither curve decides a speed, so they only have to be valid
+        let config = r#"
+            [fan_curve]
+            raw_curve = [[40, 0], [70, 50]]
+
+            [curves.unused]
+            raw_curve = [[40, 50]]
+
+            [[fans]]
+            mode = "pid"
+            pid = { setpoint = 50 }
+        "#;
+        assert!(messages(config).is_empty());
+        assert_eq!(
+            messages("[fan_curve]\nraw_curve = []\n[[fans]]\non_temp = 60"),
+            vec!["fan_curve: the curve has no points"]
+        );
+        assert_eq!(
+            messages("[fan_curve]\nraw_curve = [[40, 0], [70, 100]]\n[curves.x]\nraw_curve = []"),
+            vec!["curves.x: the curve has no points"]
+        );
+    }
+
+    #[test]
+    fn sensor_problems() {
+        let config = r#"
+            [fan_curve]
+            raw_curve = [[40, 0], [70, 100]]
+
+            [[sensors]]
+            name = "both"
+            path = "/sys/class/thermal/thermal_zone0/temp"
+            hwmon = "nvme"
+
+            [[sensors]]
+            name = "median"
+            thermal_zone = "cpu-thermal"
+            filter = { type = "median", samples = 0 }
+        "#;
+        assert_eq!(
+            messages(config),
+            vec![
+                "both: sensors need exactly one of `path`, `thermal_zone` or `hwmon`",
+                "median: the filter's `samples` has to be at least 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use crate::curve::{Curve, CurveError, Interpolation};
+use crate::error::Error;
+use crate::fan::{Channel, FanKind, FanMode, Polarity};
+use crate::filter::Filter;
+use crate::layers::Layers;
+use crate::sensor::Aggregation;
+use crate::stall::StallAction;
+use crate::tach::PULSES_PER_REVOLUTION;
+use serde::{de, Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// The newest config layout this version understands.
+///
+/// Version 1 configs, without a `version` key, are read as well. They may
+/// still use the layout of the original example config, `[curve]` with a
+/// `fan-curve` list, which `migrate` renames to `[fan_curve]` and
+/// `raw_curve` before parsing.
+pub const CONFIG_VERSION: u32 = 2;
+
+pub const DEFAULT_PATH: &str = "/etc/pi-fan.toml";
+
+/// The daemon's config file, `DEFAULT_PATH` unless told otherwise. Unknown
+/// keys are rejected so typos don't go unnoticed.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_version", deserialize_with = "version")]
+    pub version: u32,
+    pub settings: Settings,
+    pub fan_curve: RawCurve,
+    #[serde(default)]
+    pub curves: HashMap<String, RawCurve>, // named curves sensors can refer to
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>, // defaults to thermal_zone0 when empty
+    #[serde(default)]
+    pub fans: Vec<FanConfig>, // defaults to a single fan on PWM0 when empty
+}
+
+impl Config {
+    /// Parses a config file's text, migrating the old layout if it is a
+    /// version 1 config.
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        let mut value: Value = toml::from_str(text)?;
+        let version = version_of(&value);
+        if migrate(&mut value, version).map_err(de::Error::custom)? {
+            value.try_into()
+        } else {
+            // Straight from the text, so errors point at a line
+            toml::from_str(text)
+        }
+    }
+
+    /// Reads the config file at `path` together with its drop-ins and
+    /// `PI_FAN_*` environment variables, see `layers`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        Layers::read(path.as_ref(), env::vars())?.config()
+    }
+
+    /// The configured fans, or a single fan on PWM0 when there are none.
+    pub fn fan_configs(&self) -> Cow<'_, [FanConfig]> {
+        if self.fans.is_empty() {
+            Cow::Owned(vec![FanConfig::default()])
+        } else {
+            Cow::Borrowed(&self.fans)
+        }
+    }
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+/// The `version` of a config document, 1 if it has none.
+pub fn version_of(value: &Value) -> i64 {
+    value
+        .get("version")
+        .and_then(Value::as_integer)
+        .unwrap_or(default_version().into())
+}
+
+/// Renames `[curve]` and `fan-curve` from the original example config to
+/// `[fan_curve]` and `raw_curve` in a config document or drop-in belonging
+/// to a config of `version`, returning whether anything was renamed. Later
+/// versions have to use the new names.
+pub fn migrate(value: &mut Value, version: i64) -> Result<bool, String> {
+    let mut renamed = rename(value, "curve", "fan_curve")?;
+    if let Some(raw_curve) = value.get_mut("fan_curve") {
+        renamed |= rename(raw_curve, "fan-curve", "raw_curve")?;
+    }
+    if let Some(curves) = value.get_mut("curves").and_then(Value::as_table_mut) {
+        for (_, raw_curve) in curves.iter_mut() {
+            renamed |= rename(raw_curve, "fan-curve", "raw_curve")?;
+        }
+    }
+    if renamed && version > 1 {
+        return Err(format!(
+            "`[curve]` and `fan-curve` are only allowed in version 1 configs, \
+             version {} uses `[fan_curve]` and `raw_curve`",
+            version
+        ));
+    }
+    Ok(renamed)
+}
+
+fn rename(value: &mut Value, old: &str, new: &str) -> Result<bool, String> {
+    let table = match value.as_table_mut() {
+        Some(table) => table,
+        None => return Ok(false),
+    };
+    let moved = match table.remove(old) {
+        Some(moved) => moved,
+        None => return Ok(false),
+    };
+    if table.contains_key(new) {
+        return Err(format!("`{}` and `{}` can't both be set", old, new));
+    }
+    table.insert(new.to_string(), moved);
+    Ok(true)
+}
+
+fn version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let version = u32::deserialize(deserializer)?;
+    if version == 0 || version > CONFIG_VERSION {
+        return Err(de::Error::custom(format!(
+            "unsupported config version {}, expected 1 to {}",
+            version, CONFIG_VERSION
+        )));
+    }
+    Ok(version)
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub update_rate: f32, // update rate in seconds
+    #[serde(default)]
+    pub aggregation: Aggregation, // how readings from several sensors are combined
+    pub status_file: Option<PathBuf>, // rewritten with every fan's state on each update
+    #[serde(default = "default_log_interval")]
+    pub log_interval: f32, // seconds between status lines in the log, 0 to disable
+    #[serde(default)]
+    pub watch_config: bool, // reload when the config or its drop-ins change, not just on SIGHUP
+}
+
+fn default_log_interval() -> f32 {
+    60.0
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RawCurve {
+    pub raw_curve: Vec<(f32, f32)>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+impl TryFrom<&RawCurve> for Curve {
+    type Error = CurveError;
+
+    fn try_from(raw_curve: &RawCurve) -> Result<Self, Self::Error> {
+        Ok(Curve::new(raw_curve.raw_curve.clone())?.with_interpolation(raw_curve.interpolation))
+    }
+}
+
+/// A temperature sensor, selected by exactly one of `path`, `thermal_zone`
+/// or `hwmon`.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    pub name: Option<String>,
+    pub path: Option<PathBuf>,        // sysfs file reporting millidegrees
+    pub thermal_zone: Option<String>, // thermal zone `type`, e.g. "cpu-thermal"
+    pub hwmon: Option<String>,        // hwmon device `name`, e.g. "nvme"
+    pub input: Option<String>,        // hwmon input, defaults to the first one
+    #[serde(default = "default_weight")]
+    pub weight: f32, // only used by the `weighted` aggregation
+    pub curve: Option<String>,        // name of a curve in `curves` to use instead of `fan_curve`
+    pub filter: Option<Filter>,       // smoothing, e.g. `{ type = "median", samples = 5 }`
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// A fan on one of the hardware PWM channels or a GPIO pin.
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FanConfig {
+    pub name: Option<String>,
+    #[serde(default, rename = "type")]
+    pub kind: FanKind,
+    #[serde(default)]
+    pub channel: Channel, // for hardware PWM
+    pub pin: Option<u8>, // BCM pin number for software PWM and switch fans
+    #[serde(default)]
+    pub polarity: Polarity,
+    #[serde(default)]
+    pub mode: FanMode,
+    pub pid: Option<PidConfig>,       // gains for the `rpm` mode
+    pub frequency: Option<f64>,       // PWM frequency in Hz, defaults depend on `type`
+    pub curve: Option<String>,        // name of a curve in `curves`, defaults to `fan_curve`
+    pub sensors: Option<Vec<String>>, // names of the sensors driving this fan, defaults to all
+    pub on_temp: Option<f32>, // switch fully on at this temperature instead of following the curve
+    pub off_temp: Option<f32>, // and back off at this one, defaults to `SWITCH_HYSTERESIS` below
+    #[serde(default)]
+    pub hysteresis: f32, // °C the temperature has to drop before the curve's demand does
+    pub max_rise: Option<f32>, // fastest the duty may go up in %/s, unlimited by default
+    pub max_fall: Option<f32>, // and down
+    pub tach: Option<TachConfig>,
+    pub stall: Option<StallConfig>, // needs `tach`
+}
+
+impl FanConfig {
+    /// The fan's `name`, or `fan i` for the `i`th fan if it has none.
+    pub fn name(&self, i: usize) -> String {
+        self.name.clone().unwrap_or_else(|| format!("fan {}", i))
+    }
+
+    /// Whether `other` drives the fan through the same output and
+    /// tachometer, so they don't need to be reopened when switching to it.
+    pub fn same_wiring(&self, other: &FanConfig) -> bool {
+        let tach = |config: &FanConfig| {
+            config
+                .tach
+                .as_ref()
+                .map(|tach| (tach.pin, tach.pulses_per_revolution))
+        };
+        self.kind == other.kind
+            && self.channel == other.channel
+            && self.pin == other.pin
+            && self.polarity == other.polarity
+            && self.frequency == other.frequency
+            && tach(self) == tach(other)
+    }
+}
+
+pub const SWITCH_HYSTERESIS: f32 = 5.0; // °C between `on_temp` and the default `off_temp`
+
+/// The tachometer wire of a 4-pin fan.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TachConfig {
+    pub pin: u8, // BCM pin number
+    #[serde(default = "default_pulses_per_revolution")]
+    pub pulses_per_revolution: u32,
+}
+
+fn default_pulses_per_revolution() -> u32 {
+    PULSES_PER_REVOLUTION
+}
+
+/// When a fan with a tachometer counts as stalled and what happens then.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StallConfig {
+    #[serde(default = "default_min_rpm")]
+    pub min_rpm: f32, // anything slower counts as stalled
+    pub max_rpm: Option<f32>, // RPM at 100%, to also catch fans far below their expected speed
+    #[serde(default)]
+    pub min_duty: f32, // duty at or below which the fan may stop on its own
+    #[serde(default = "default_stall_seconds")]
+    pub grace: f32, // seconds a fan may look stalled before it is kick-started
+    #[serde(default = "default_stall_seconds")]
+    pub kick: f32, // seconds to run at full speed when kick-starting
+    #[serde(default)]
+    pub action: StallAction,
+    pub command: Option<String>, // run through `sh -c` when a fan stays stalled
+}
+
+fn default_min_rpm() -> f32 {
+    100.0
+}
+
+fn default_stall_seconds() -> f32 {
+    3.0
+}
+
+/// Tuning of a PID loop. Unset gains use defaults that depend on the mode.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PidConfig {
+    pub kp: Option<f32>,
+    pub ki: Option<f32>,
+    pub kd: Option<f32>,
+    #[serde(default)]
+    pub min_duty: f32,
+    #[serde(default = "default_max_duty")]
+    pub max_duty: f32,
+    pub setpoint: Option<f32>, // temperature to hold in the `pid` mode
+    #[serde(default)]
+    pub derivative_filter: f32, // time constant in seconds smoothing the derivative, 0 for none
+}
+
+fn default_max_duty() -> f32 {
+    100.0
+}
+
+// The same as an empty `[pid]` table, for fans without one
+impl Default for PidConfig {
+    fn default() -> Self {
+        PidConfig {
+            kp: None,
+            ki: None,
+            kd: None,
+            min_duty: 0.0,
+            max_duty: default_max_duty(),
+            setpoint: None,
+            derivative_filter: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, PidConfig};
+    use crate::check;
+    use std::{fs, path::Path};
+
+    #[test]
+    fn shipped_examples() {
+        let res = Path::new(env!("CARGO_MANIFEST_DIR")).join("res");
+        let mut examples = 0;
+        for entry in fs::read_dir(res).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            let text = fs::read_to_string(&path).unwrap();
+            if let Err(err) = Config::parse(&text) {
+                panic!("{}: {}", path.display(), err);
+            }
+            assert_eq!(check::check(&text), vec![], "{}", path.display());
+            examples += 1;
+        }
+        assert!(examples > 0);
+    }
+
+    #[test]
+    fn versions() {
+        let old_layout = "[settings]\nupdate_rate = 1.0\n[curve]\nfan-curve = [[40, 0], [70, 100]]";
+        let config = Config::parse(old_layout).unwrap();
+        assert_eq!(config.version, 1);
+        assert_eq!(config.fan_curve.raw_curve, vec![(40.0, 0.0), (70.0, 100.0)]);
+        let err = Config::parse(&format!("version = 2\n{}", old_layout)).err();
+        assert!(err
+            .unwrap()
+            .to_string()
+            .contains("only allowed in version 1 configs"));
+
+        let config = "version = 3\n[settings]\nupdate_rate = 1.0\n[fan_curve]\nraw_curve = []";
+        let err = toml::from_str::<Config>(config).err().unwrap();
+        assert!(err.to_string().contains("unsupported config version 3"));
+
+        let config = "[settings]\nupdate_rate = 1.0\nupdate_rat = 2.0\n[fan_curve]\nraw_curve = []";
+        let err = toml::from_str::<Config>(config).err().unwrap();
+        assert!(err.to_string().contains("unknown field `update_rat`"));
+    }
+
+    #[test]
+    fn pid_defaults() {
+        let empty: PidConfig = toml::from_str("").unwrap();
+        let default = PidConfig::default();
+        assert_eq!((empty.min_duty, empty.max_duty), (0.0, 100.0));
+        assert_eq!((default.min_duty, default.max_duty), (0.0, 100.0));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::curve::Curve;
+use crate::fan::FanOutput;
+use crate::sensor::{Aggregation, SensorGroup};
+use crate::stall::StallEvent;
+use std::{fmt, io};
+
+pub const FAIL_SPEED: f32 = 50.0;
+
+/// What a single `update_speed` call read and set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tick {
+    pub temp: Option<f32>,
+    pub speed: f32,
+    pub rpm: Option<f32>,        // only known for fans with a tachometer
+    pub target_rpm: Option<f32>, // for fans in `rpm` mode
+    pub stall: Option<StallEvent>,
+    pub sensor_failed: bool, // a sensor with its own curve couldn't be read
+}
+
+impl fmt::Display for Tick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.temp {
+            Some(temp) => write!(f, "{:.1}°C", temp)?,
+            None => write!(f, "-°C")?,
+        }
+        write!(f, ", duty {:.1}%", self.speed)?;
+        if let Some(rpm) = self.rpm {
+            write!(f, ", {:.0} rpm", rpm)?;
+        }
+        if let Some(target_rpm) = self.target_rpm {
+            write!(f, " (target {:.0})", target_rpm)?;
+        }
+        match self.stall {
+            Some(StallEvent::KickStart) => write!(f, ", kick-starting")?,
+            Some(StallEvent::Stalled) => write!(f, ", stalled")?,
+            Some(StallEvent::Recovered) => write!(f, ", recovered")?,
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+/// Returns the speed for a temperature reading, falling back to
+/// `FAIL_SPEED` when the sensor couldn't be read.
+pub fn get_speed(temp: Option<f32>, curve: &Curve) -> f32 {
+    match temp {
+        Some(temp) => curve.get_value_at(temp),
+        None => FAIL_SPEED,
+    }
+}
+
+/// Reads `sensors` once and drives `fan` at the speed from `get_demand`.
+pub fn update_speed(
+    sensors: &mut SensorGroup,
+    fan: &mut dyn FanOutput,
+    curve: &Curve,
+) -> io::Result<Tick> {
+    let tick = get_demand(sensors, curve);
+    fan.set_speed(tick.speed)?;
+    Ok(tick)
+}
+
+/// Reads `sensors` once and returns the highest speed demanded by any of
+/// them. Sensors with their own curve are evaluated against it, the others
+/// are aggregated and evaluated against `curve`. A sensor with its own curve
+/// that can't be read demands `FAIL_SPEED`, since no other sensor covers it,
+/// and sets `sensor_failed`.
+pub fn get_demand(sensors: &mut SensorGroup, curve: &Curve) -> Tick {
+    demand(sensors, curve, None)
+}
+
+/// Like `get_demand`, but passes every curve's demand through `hysteresis`
+/// along with the temperature it was evaluated at.
+pub fn get_held_demand(
+    sensors: &mut SensorGroup,
+    curve: &Curve,
+    hysteresis: &mut Hysteresis,
+) -> Tick {
+    demand(sensors, curve, Some(hysteresis))
+}
+
+fn demand(
+    sensors: &mut SensorGroup,
+    curve: &Curve,
+    mut hysteresis: Option<&mut Hysteresis>,
+) -> Tick {
+    let readings = sensors.read();
+    let combined = sensors.combine(&readings);
+
+    // Each sensor is its own curve input, the aggregate comes after them
+    let mut demands = Vec::new();
+    let mut demand = |input: usize, temp: Option<f32>, speed: f32| match &mut hysteresis {
+        Some(hysteresis) => demands.push(hysteresis.update(input, temp, speed)),
+        None => demands.push(speed),
+    };
+    let mut sensor_failed = false;
+    for (i, (reading, own_curve)) in readings.iter().zip(sensors.curves()).enumerate() {
+        match (reading, own_curve) {
+            (reading, Some(own_curve)) => {
+                sensor_failed |= reading.is_none();
+                demand(i, *reading, get_speed(*reading, own_curve))
+            }
+            (Some(temp), None) if sensors.aggregation() == Aggregation::MaxCurve => {
+                demand(i, Some(*temp), curve.get_value_at(*temp))
+            }
+            _ => (),
+        }
+    }
+    if sensors.aggregation() != Aggregation::MaxCurve {
+        if let Some(temp) = combined {
+            demand(readings.len(), Some(temp), curve.get_value_at(temp));
+        }
+    }
+
+    let speed = match demands.into_iter().reduce(f32::max) {
+        Some(speed) => speed,
+        None => get_speed(None, curve),
+    };
+    let temp = combined.or_else(|| readings.iter().flatten().copied().reduce(f32::max));
+
+    Tick {
+        temp,
+        speed,
+        rpm: None,
+        target_rpm: None,
+        stall: None,
+        sensor_failed,
+    }
+}
+
+/// On/off control with separate thresholds, so a fan that can only be
+/// switched doesn't toggle on every small temperature change.
+pub struct Thermostat {
+    on_temp: f32,
+    off_temp: f32,
+    running: bool,
+}
+
+impl Thermostat {
+    pub fn new(on_temp: f32, off_temp: f32) -> Self {
+        Thermostat {
+            on_temp,
+            off_temp,
+            running: false,
+        }
+    }
+
+    /// Returns 100% once `temp` reaches `on_temp` and keeps it there until it
+    /// drops to `off_temp`. Runs the fan when the temperature is unknown.
+    pub fn update(&mut self, temp: Option<f32>) -> f32 {
+        self.running = match temp {
+            Some(temp) if temp >= self.on_temp => true,
+            Some(temp) if temp <= self.off_temp => false,
+            Some(_) => self.running,
+            None => true,
+        };
+        if self.running {
+            100.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Holds a curve's demand while the temperature is falling, so readings
+/// hovering around a curve point don't make the fan hunt. The demand only
+/// drops once the temperature is `degrees` below where it was set. Each
+/// curve input, such as a sensor with its own curve, is held separately.
+pub struct Hysteresis {
+    degrees: f32,
+    held: Vec<Option<(f32, f32)>>, // per input, the temperature and the demand set at it
+}
+
+impl Hysteresis {
+    pub fn new(degrees: f32) -> Self {
+        Hysteresis {
+            degrees,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, input: usize, temp: Option<f32>, demand: f32) -> f32 {
+        if self.held.len() <= input {
+            self.held.resize(input + 1, None);
+        }
+        let held = &mut self.held[input];
+        let temp = match temp {
+            Some(temp) => temp,
+            // Nothing to compare against, so don't hold anything
+            None => {
+                *held = None;
+                return demand;
+            }
+        };
+        match *held {
+            Some((held_temp, held_demand))
+                if demand < held_demand && temp > held_temp - self.degrees =>
+            {
+                held_demand
+            }
+            _ => {
+                *held = Some((temp, demand));
+                demand
+            }
+        }
+    }
+}
+
+/// Limits how fast the duty cycle can change, in percent per second, with
+/// separate limits for speeding up and slowing down.
+pub struct RampLimiter {
+    max_rise: Option<f32>,
+    max_fall: Option<f32>,
+    last: Option<f32>,
+}
+
+impl RampLimiter {
+    pub fn new(max_rise: Option<f32>, max_fall: Option<f32>) -> Self {
+        RampLimiter {
+            max_rise,
+            max_fall,
+            last: None,
+        }
+    }
+
+    /// Moves towards `speed` as far as the limits allow in `dt` seconds.
+    pub fn update(&mut self, speed: f32, dt: f32) -> f32 {
+        let speed = match self.last {
+            Some(last) => {
+                let max = self.max_rise.map_or(f32::INFINITY, |rate| last + rate * dt);
+                let min = self
+                    .max_fall
+                    .map_or(f32::NEG_INFINITY, |rate| last - rate * dt);
+                speed.min(max).max(min)
+            }
+            None => speed,
+        };
+        self.last = Some(speed);
+        speed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_held_demand, update_speed, Hysteresis, RampLimiter, Thermostat, FAIL_SPEED};
+    use crate::curve::Curve;
+    use crate::sensor::{Aggregation, SensorGroup};
+    use crate::sim::{MockFan, TraceSensor};
+
+    #[test]
+    fn follows_curve() {
+        let curve = Curve::new(vec![(20.0, 0.0), (40.0, 50.0), (60.0, 100.0)]).unwrap();
+        let mut fan = MockFan::default();
+        for temp in [10.0, 30.0, 50.0, 60.0] {
+            let mut sensors = SensorGroup::single(TraceSensor::new(vec![temp]));
+            update_speed(&mut sensors, &mut fan, &curve).unwrap();
+        }
+        assert_eq!(fan.history(), vec![0.0, 25.0, 75.0, 100.0]);
+    }
+
+    #[test]
+    fn failed_sensor() {
+        let curve = Curve::new(vec![(20.0, 0.0), (60.0, 100.0)]).unwrap();
+        let mut fan = MockFan::default();
+        let mut sensors = SensorGroup::single(TraceSensor::new(vec![]));
+        let tick = update_speed(&mut sensors, &mut fan, &curve).unwrap();
+        assert_eq!(tick.temp, None);
+        assert_eq!(tick.speed, FAIL_SPEED);
+        assert_eq!(fan.history(), vec![FAIL_SPEED]);
+    }
+
+    #[test]
+    fn max_of_curve_outputs() {
+        // A curve that isn't monotonic, so the hottest sensor isn't the one
+        // demanding the most
+        let curve = Curve::new(vec![(30.0, 0.0), (40.0, 80.0), (60.0, 40.0)]).unwrap();
+        let mut sensors = SensorGroup::new(Aggregation::MaxCurve);
+        sensors.add("soc", TraceSensor::new(vec![60.0]), 1.0);
+        sensors.add("nvme", TraceSensor::new(vec![40.0]), 1.0);
+        let tick = update_speed(&mut sensors, &mut MockFan::default(), &curve).unwrap();
+        assert_eq!(tick.temp, Some(60.0));
+        assert_eq!(tick.speed, 80.0);
+    }
+
+    #[test]
+    fn per_sensor_curves() {
+        let soc = Curve::new(vec![(50.0, 0.0), (70.0, 100.0)]).unwrap();
+        let nvme = Curve::new(vec![(40.0, 0.0), (50.0, 100.0)]).unwrap();
+        let mut sensors = SensorGroup::new(Aggregation::Max);
+        sensors.add("soc", TraceSensor::new(vec![55.0]), 1.0);
+        sensors.add_with_curve("nvme", TraceSensor::new(vec![45.0]), nvme);
+        let tick = update_speed(&mut sensors, &mut MockFan::default(), &soc).unwrap();
+        assert_eq!(tick.temp, Some(55.0));
+        assert_eq!(tick.speed, 50.0);
+
+        let mut sensors = SensorGroup::new(Aggregation::Max);
+        sensors.add_with_curve("nvme", TraceSensor::new(vec![]), soc.clone());
+        let tick = update_speed(&mut sensors, &mut MockFan::default(), &soc).unwrap();
+        assert_eq!(tick.speed, FAIL_SPEED);
+
+        // A dead sensor with its own curve isn't ignored because others work
+        let nvme = Curve::new(vec![(40.0, 0.0), (50.0, 100.0)]).unwrap();
+        let mut sensors = SensorGroup::new(Aggregation::Max);
+        sensors.add("soc", TraceSensor::new(vec![45.0]), 1.0);
+        sensors.add_with_curve("nvme", TraceSensor::new(vec![]), nvme);
+        let tick = update_speed(&mut sensors, &mut MockFan::default(), &soc).unwrap();
+        assert_eq!(tick.temp, Some(45.0));
+        assert_eq!(tick.speed, FAIL_SPEED);
+        assert!(tick.sensor_failed);
+    }
+
+    #[test]
+    fn thermostat_hysteresis() {
+        let mut thermostat = Thermostat::new(60.0, 50.0);
+        let speeds: Vec<f32> = [55.0, 60.0, 55.0, 50.0, 55.0, 65.0]
+            .into_iter()
+            .map(|temp| thermostat.update(Some(temp)))
+            .collect();
+        assert_eq!(speeds, vec![0.0, 100.0, 100.0, 0.0, 0.0, 100.0]);
+        assert_eq!(Thermostat::new(60.0, 50.0).update(None), 100.0);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut hysteresis = Hysteresis::new(3.0);
+        assert_eq!(hysteresis.update(0, Some(60.0), 50.0), 50.0);
+        assert_eq!(hysteresis.update(0, Some(59.0), 45.0), 50.0);
+        assert_eq!(hysteresis.update(0, Some(58.0), 40.0), 50.0);
+        assert_eq!(hysteresis.update(0, Some(61.0), 55.0), 55.0);
+        assert_eq!(hysteresis.update(0, Some(58.0), 40.0), 40.0);
+        assert_eq!(hysteresis.update(0, None, 30.0), 30.0);
+    }
+
+    #[test]
+    fn hysteresis_per_sensor_curve() {
+        // The SoC holds steady while the NVMe cools down, so the NVMe's
+        // demand has to drop with its own temperature
+        let soc = Curve::new(vec![(50.0, 0.0), (70.0, 100.0)]).unwrap();
+        let nvme = Curve::new(vec![(40.0, 0.0), (60.0, 100.0)]).unwrap();
+        let mut sensors = SensorGroup::new(Aggregation::Max);
+        sensors.add("soc", TraceSensor::new(vec![50.0; 4]), 1.0);
+        sensors.add_with_curve("nvme", TraceSensor::new(vec![60.0, 58.0, 50.0, 40.0]), nvme);
+        let mut hysteresis = Hysteresis::new(3.0);
+        let speeds: Vec<f32> = (0..4)
+            .map(|_| get_held_demand(&mut sensors, &soc, &mut hysteresis).speed)
+            .collect();
+        assert_eq!(speeds, vec![100.0, 100.0, 50.0, 0.0]);
+    }
+
+    #[test]
+    fn ramp_limits() {
+        let mut ramp = RampLimiter::new(Some(10.0), Some(2.0));
+        assert_eq!(ramp.update(40.0, 1.0), 40.0);
+        assert_eq!(ramp.update(100.0, 1.0), 50.0);
+        assert_eq!(ramp.update(100.0, 2.0), 70.0);
+        assert_eq!(ramp.update(0.0, 1.0), 68.0);
+
+        let mut ramp = RampLimiter::new(None, Some(5.0));
+        assert_eq!(ramp.update(0.0, 1.0), 0.0);
+        assert_eq!(ramp.update(100.0, 1.0), 100.0);
+        assert_eq!(ramp.update(0.0, 1.0), 95.0);
+    }
+}
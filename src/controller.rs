@@ -0,0 +1,1079 @@
+//! Runtime state built from a `Config`: every fan with its sensors, curve
+//! and output, updated together from the daemon loop.
+
+use crate::config::{Config, FanConfig, RawCurve, SensorConfig, TachConfig, SWITCH_HYSTERESIS};
+use crate::control::{self, Hysteresis, RampLimiter, Thermostat, Tick, FAIL_SPEED};
+use crate::curve::Curve;
+use crate::error::Error;
+use crate::fan::{self, Channel, FanKind, FanMode, FanOutput};
+use crate::filter::Filtered;
+use crate::pid::{self, Pid};
+use crate::sensor::{self, SensorGroup, SensorInfo, SysfsSensor, TemperatureSource};
+use crate::stall::{self, StallAction, StallDetector, StallEvent};
+use crate::tach::{self, RpmSource};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Opens the devices fans are connected to, so a `Controller` can be built
+/// from a config without real hardware.
+pub trait Hardware {
+    fn open_fan(&mut self, config: &FanConfig) -> io::Result<Box<dyn FanOutput>>;
+    fn open_tach(&mut self, config: &TachConfig) -> io::Result<Box<dyn RpmSource>>;
+}
+
+/// The Pi's PWM channels and GPIO pins.
+pub struct RaspberryPi;
+
+impl Hardware for RaspberryPi {
+    fn open_fan(&mut self, config: &FanConfig) -> io::Result<Box<dyn FanOutput>> {
+        fan::open(config)
+    }
+
+    fn open_tach(&mut self, config: &TachConfig) -> io::Result<Box<dyn RpmSource>> {
+        tach::open(config)
+    }
+}
+
+/// A single fan and everything needed to compute its speed.
+pub struct FanControl {
+    name: String,
+    sensors: SensorGroup,
+    curve: Curve,
+    regulation: Regulation,
+    hysteresis: Option<Hysteresis>,
+    ramp: Option<RampLimiter>,
+    output: Box<dyn FanOutput>,
+    tach: Option<Box<dyn RpmSource>>,
+    tach_failing: bool, // the last tachometer read failed and was logged
+    stall: Option<StallGuard>,
+    last_tick: Option<Tick>,
+}
+
+// How a fan's duty cycle is chosen
+enum Regulation {
+    Curve, // straight from the curve
+    Thermostat(Thermostat),
+    Rpm(Pid), // the curve gives target RPM, reached from tachometer feedback
+    Setpoint { temp: f32, pid: Pid }, // holds a temperature, ignoring the curve
+}
+
+// Stall detection and the configured response for one fan
+struct StallGuard {
+    detector: StallDetector,
+    action: StallAction,
+    command: Option<String>,
+}
+
+impl FanControl {
+    pub fn new<F: FanOutput + 'static>(
+        name: &str,
+        sensors: SensorGroup,
+        curve: Curve,
+        output: F,
+    ) -> Self {
+        FanControl {
+            name: name.to_string(),
+            sensors,
+            curve,
+            regulation: Regulation::Curve,
+            hysteresis: None,
+            ramp: None,
+            output: Box::new(output),
+            tach: None,
+            tach_failing: false,
+            stall: None,
+            last_tick: None,
+        }
+    }
+
+    /// Switches the fan fully on and off at fixed temperatures instead of
+    /// following the curve.
+    pub fn with_thermostat(mut self, thermostat: Thermostat) -> Self {
+        self.regulation = Regulation::Thermostat(thermostat);
+        self
+    }
+
+    /// Treats the curve as target RPM and sets the duty with `pid` based on
+    /// the tachometer.
+    pub fn with_rpm_control(mut self, pid: Pid) -> Self {
+        self.regulation = Regulation::Rpm(pid);
+        self
+    }
+
+    /// Ignores the curve and sets the duty with `pid` to hold the
+    /// temperature at `temp`.
+    pub fn with_setpoint(mut self, temp: f32, pid: Pid) -> Self {
+        self.regulation = Regulation::Setpoint { temp, pid };
+        self
+    }
+
+    /// Only lowers the curve's demand once the temperature has dropped by
+    /// `degrees`.
+    pub fn with_hysteresis(mut self, degrees: f32) -> Self {
+        self.hysteresis = Some(Hysteresis::new(degrees));
+        self
+    }
+
+    /// Limits how fast the duty cycle rises and falls, in percent per second.
+    pub fn with_ramp_limits(mut self, max_rise: Option<f32>, max_fall: Option<f32>) -> Self {
+        self.ramp = Some(RampLimiter::new(max_rise, max_fall));
+        self
+    }
+
+    pub fn with_tach<T: RpmSource + 'static>(mut self, tach: T) -> Self {
+        self.tach = Some(Box::new(tach));
+        self
+    }
+
+    /// Watches the tachometer for stalls, responding with `action` and
+    /// running `command` if the fan can't be kick-started.
+    pub fn with_stall_detection(
+        mut self,
+        detector: StallDetector,
+        action: StallAction,
+        command: Option<String>,
+    ) -> Self {
+        self.stall = Some(StallGuard {
+            detector,
+            action,
+            command,
+        });
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The result of the last successful `update`.
+    pub fn last_tick(&self) -> Option<Tick> {
+        self.last_tick
+    }
+
+    /// What happens when this fan stalls, if it is being watched.
+    pub fn stall_action(&self) -> Option<StallAction> {
+        self.stall.as_ref().map(|guard| guard.action)
+    }
+
+    /// Whether the fan stayed stalled after being kick-started and hasn't
+    /// recovered since.
+    pub fn is_stalled(&self) -> bool {
+        self.stall
+            .as_ref()
+            .is_some_and(|guard| guard.detector.is_stalled())
+    }
+
+    /// Updates the fan once; `dt` is the time in seconds since the last
+    /// update.
+    pub fn update(&mut self, dt: f32) -> io::Result<Tick> {
+        let mut tick = match &mut self.hysteresis {
+            Some(hysteresis) => {
+                control::get_held_demand(&mut self.sensors, &self.curve, hysteresis)
+            }
+            None => control::get_demand(&mut self.sensors, &self.curve),
+        };
+        if let Some(tach) = &mut self.tach {
+            // Only log when the tachometer starts or stops failing
+            let rpm = tach.read_rpm();
+            match &rpm {
+                Err(err) if !self.tach_failing => {
+                    eprintln!("Failed to read tachometer of {}: {}", self.name, err)
+                }
+                Ok(_) if self.tach_failing => {
+                    eprintln!("Tachometer of {} can be read again", self.name)
+                }
+                _ => (),
+            }
+            self.tach_failing = rpm.is_err();
+            tick.rpm = rpm.ok();
+        }
+        tick.speed = match &mut self.regulation {
+            Regulation::Curve => tick.speed,
+            Regulation::Thermostat(thermostat) => thermostat.update(tick.temp),
+            Regulation::Rpm(pid) => match (tick.temp, tick.rpm) {
+                // `FAIL_SPEED` is a duty, not a target for the PID loop
+                _ if tick.sensor_failed => FAIL_SPEED,
+                (Some(_), Some(rpm)) => {
+                    tick.target_rpm = Some(tick.speed);
+                    pid.update(tick.speed - rpm, dt)
+                }
+                // Hold the duty until the tachometer can be read again
+                (Some(_), None) => self.last_tick.map_or(FAIL_SPEED, |last| last.speed),
+                (None, _) => FAIL_SPEED,
+            },
+            Regulation::Setpoint {
+                temp: setpoint,
+                pid,
+            } => match tick.temp {
+                Some(temp) => pid.update(temp - *setpoint, dt),
+                None => FAIL_SPEED,
+            },
+        };
+        if let Some(ramp) = &mut self.ramp {
+            tick.speed = ramp.update(tick.speed, dt);
+        }
+        if let Some(guard) = &mut self.stall {
+            let (speed, event) = guard.detector.update(tick.speed, tick.rpm, dt);
+            tick.speed = speed;
+            tick.stall = event;
+            if let Some(event) = event {
+                self.raise(event, &tick);
+            }
+        }
+
+        self.output.set_speed(tick.speed)?;
+        self.last_tick = Some(tick);
+        Ok(tick)
+    }
+
+    fn raise(&self, event: StallEvent, tick: &Tick) {
+        let guard = self.stall.as_ref().unwrap();
+        match event {
+            StallEvent::KickStart => eprintln!("{} looks stalled, kick-starting it", self.name),
+            StallEvent::Recovered => eprintln!("{} is spinning again", self.name),
+            StallEvent::Stalled => {
+                eprintln!("{} is stalled: {}", self.name, tick);
+                if let Some(command) = &guard.command {
+                    if let Err(err) = stall::run_hook(command, &self.name, tick) {
+                        eprintln!("Failed to run stall command {:?}: {}", command, err);
+                    }
+                }
+                // `Throttle` is up to the `Controller`, which knows when no
+                // fan needs it anymore, and `Exit` to the caller
+                if guard.action == StallAction::Shutdown {
+                    if let Err(err) = stall::shutdown() {
+                        eprintln!("Failed to shut down after {} stalled: {}", self.name, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Controller {
+    fans: Vec<FanControl>,
+    config: Option<Config>, // what the fans were built from, to compare reloads against
+    interval: f32,          // seconds between updates
+    throttled: Option<Vec<(PathBuf, String)>>, // CPU frequency limits to restore after stalls
+}
+
+// Everything about a fan that can change without reopening its hardware
+struct Tuning {
+    sensors: SensorGroup,
+    curve: Curve,
+    regulation: Regulation,
+    hysteresis: Option<Hysteresis>,
+    ramp: Option<RampLimiter>,
+    stall: Option<StallGuard>,
+}
+
+impl Tuning {
+    fn from_config(
+        config: &Config,
+        fan_config: &FanConfig,
+        name: &str,
+        available: &[SensorInfo],
+    ) -> Result<Self, Error> {
+        let sensors = open_sensors(config, fan_config, available)?;
+        let curve = named_curve(config, fan_config.curve.as_deref())?;
+        let regulation =
+            regulation(fan_config).map_err(|err| Error::invalid(format!("{}: {}", name, err)))?;
+        let (hysteresis, ramp) =
+            smoothing(fan_config).map_err(|err| Error::invalid(format!("{}: {}", name, err)))?;
+        Ok(Tuning {
+            sensors,
+            curve,
+            regulation,
+            hysteresis,
+            ramp,
+            stall: fan_config.stall.as_ref().map(|stall_config| StallGuard {
+                detector: StallDetector::new(stall_config),
+                action: stall_config.action,
+                command: stall_config.command.clone(),
+            }),
+        })
+    }
+}
+
+impl Controller {
+    pub fn new(fans: Vec<FanControl>, interval: f32) -> Self {
+        Controller {
+            fans,
+            config: None,
+            interval,
+            throttled: None,
+        }
+    }
+
+    /// Builds every fan in `config`, resolving sensors against the
+    /// `available` ones from `sensor::discover` and opening outputs and
+    /// tachometers through `hardware`.
+    pub fn from_config(
+        config: &Config,
+        available: &[SensorInfo],
+        hardware: &mut dyn Hardware,
+    ) -> Result<Self, Error> {
+        check_settings(config).map_err(Error::invalid)?;
+        check_sensors(config)?;
+        let fan_configs = config.fan_configs().into_owned();
+        check_fans(config, &fan_configs)?;
+        let mut fans = Vec::new();
+        for (i, fan_config) in fan_configs.iter().enumerate() {
+            let name = fan_config.name(i);
+            let tuning = Tuning::from_config(config, fan_config, &name, available)?;
+            let output = hardware.open_fan(fan_config).map_err(|source| Error::Fan {
+                name: name.clone(),
+                kind: fan_config.kind,
+                source,
+            })?;
+            let tach =
+                match &fan_config.tach {
+                    Some(tach_config) => Some(hardware.open_tach(tach_config).map_err(
+                        |source| Error::Tach {
+                            name: name.clone(),
+                            source,
+                        },
+                    )?),
+                    None => None,
+                };
+
+            fans.push(FanControl {
+                name,
+                sensors: tuning.sensors,
+                curve: tuning.curve,
+                regulation: tuning.regulation,
+                hysteresis: tuning.hysteresis,
+                ramp: tuning.ramp,
+                output,
+                tach,
+                tach_failing: false,
+                stall: tuning.stall,
+                last_tick: None,
+            });
+        }
+        Ok(Controller {
+            fans,
+            config: Some(config.clone()),
+            interval: config.settings.update_rate,
+            throttled: None,
+        })
+    }
+
+    /// Switches to the curves, sensors and settings in `config` while
+    /// keeping the fans' outputs open, so they don't stop in between.
+    /// Either everything is replaced or, if `config` is invalid or changes
+    /// how fans are wired, nothing is.
+    ///
+    /// Sensor filters, hysteresis, ramp limits and PID loops whose settings
+    /// didn't change carry on with their state, and stall detection always
+    /// does, so a stalled fan isn't taken to be running again.
+    pub fn reload(&mut self, config: &Config, available: &[SensorInfo]) -> Result<(), Error> {
+        check_settings(config).map_err(Error::invalid)?;
+        check_sensors(config)?;
+        let fan_configs = config.fan_configs().into_owned();
+        check_fans(config, &fan_configs)?;
+        let old_config = match &self.config {
+            Some(old_config) if old_config.fan_configs().len() == fan_configs.len() => old_config,
+            _ => return Err(Error::invalid("adding or removing fans needs a restart")),
+        };
+        let old_fan_configs = old_config.fan_configs();
+
+        let mut tunings = Vec::new();
+        for (i, (old, new)) in old_fan_configs.iter().zip(&fan_configs).enumerate() {
+            let name = new.name(i);
+            if !old.same_wiring(new) {
+                return Err(Error::invalid(format!(
+                    "changing how {} is connected needs a restart",
+                    name
+                )));
+            }
+            let tuning = Tuning::from_config(config, new, &name, available)?;
+            tunings.push((name, tuning));
+        }
+
+        for (i, (fan, (name, tuning))) in self.fans.iter_mut().zip(tunings).enumerate() {
+            let (old, new) = (&old_fan_configs[i], &fan_configs[i]);
+            fan.name = name;
+            fan.curve = tuning.curve;
+            let same_sensors = same_sensors(old_config, config, old, new);
+            if !same_sensors {
+                fan.sensors = tuning.sensors;
+            }
+            if (old.mode, old.on_temp, old.off_temp, &old.pid)
+                != (new.mode, new.on_temp, new.off_temp, &new.pid)
+            {
+                fan.regulation = tuning.regulation;
+            }
+            // Hysteresis is held per sensor, so it starts over with new ones
+            if old.hysteresis != new.hysteresis || !same_sensors {
+                fan.hysteresis = tuning.hysteresis;
+            }
+            if (old.max_rise, old.max_fall) != (new.max_rise, new.max_fall) {
+                fan.ramp = tuning.ramp;
+            }
+            if old.stall != new.stall {
+                let mut stall = tuning.stall;
+                if let (Some(stall), Some(old_stall)) = (&mut stall, &fan.stall) {
+                    stall.detector.resume(&old_stall.detector);
+                }
+                fan.stall = stall;
+            }
+        }
+        self.config = Some(config.clone());
+        self.interval = config.settings.update_rate;
+        Ok(())
+    }
+
+    pub fn fans(&self) -> &[FanControl] {
+        &self.fans
+    }
+
+    /// Updates every fan once, returning the result for each in order.
+    pub fn update(&mut self) -> Vec<io::Result<Tick>> {
+        let interval = self.interval;
+        let results = self
+            .fans
+            .iter_mut()
+            .map(|fan| fan.update(interval))
+            .collect();
+        self.throttle();
+        results
+    }
+
+    // Caps the CPUs' frequency while any fan with the `throttle` action is
+    // stalled, and restores it once none is
+    fn throttle(&mut self) {
+        let stalled = self
+            .fans
+            .iter()
+            .any(|fan| fan.stall_action() == Some(StallAction::Throttle) && fan.is_stalled());
+        match (&self.throttled, stalled) {
+            (None, true) => match stall::throttle_cpu(Path::new(stall::CPUFREQ)) {
+                Ok(saved) => self.throttled = Some(saved),
+                Err(err) => {
+                    eprintln!("Failed to throttle the CPUs after a stall: {}", err);
+                    // Rather than trying again on every update
+                    self.throttled = Some(Vec::new());
+                }
+            },
+            (Some(saved), false) => {
+                if let Err(err) = stall::restore_cpu(saved) {
+                    eprintln!("Failed to restore the CPUs' frequency limits: {}", err);
+                }
+                self.throttled = None;
+            }
+            _ => (),
+        }
+    }
+
+    /// One `name: state` line per fan describing its last update.
+    pub fn status(&self) -> String {
+        let mut status = String::new();
+        for fan in self.fans.iter() {
+            match fan.last_tick {
+                Some(tick) => status.push_str(&format!("{}: {}\n", fan.name, tick)),
+                None => status.push_str(&format!("{}: not updated yet\n", fan.name)),
+            }
+        }
+        status
+    }
+
+    /// Replaces the file at `path` with `status`, going through a temporary
+    /// file so readers never see it half written.
+    pub fn write_status(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.status())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Checks the settings the controller depends on.
+pub(crate) fn check_settings(config: &Config) -> Result<(), String> {
+    if config.settings.update_rate <= 0.0 {
+        return Err(String::from("`update_rate` has to be positive"));
+    }
+    Ok(())
+}
+
+/// Checks a sensor entry without opening it.
+pub(crate) fn check_sensor(sensor_config: &SensorConfig) -> Result<(), String> {
+    let selectors = [
+        sensor_config.path.is_some(),
+        sensor_config.thermal_zone.is_some(),
+        sensor_config.hwmon.is_some(),
+    ];
+    if selectors.into_iter().filter(|&set| set).count() != 1 {
+        return Err(String::from(
+            "sensors need exactly one of `path`, `thermal_zone` or `hwmon`",
+        ));
+    }
+    match &sensor_config.filter {
+        Some(filter) => filter.check(),
+        None => Ok(()),
+    }
+}
+
+fn check_sensors(config: &Config) -> Result<(), Error> {
+    for (i, sensor_config) in config.sensors.iter().enumerate() {
+        check_sensor(sensor_config)
+            .map_err(|err| Error::invalid(format!("{}: {}", sensor_name(config, i), err)))?;
+    }
+    Ok(())
+}
+
+/// Checks everything about the `i`th of `fan_configs` that can be told
+/// without opening its sensors or hardware.
+pub(crate) fn check_fan(
+    config: &Config,
+    fan_configs: &[FanConfig],
+    i: usize,
+) -> Result<(), String> {
+    let fan_config = &fan_configs[i];
+    regulation(fan_config)?;
+    smoothing(fan_config)?;
+    if fan_config.stall.is_some() && fan_config.tach.is_none() {
+        return Err(String::from("stall detection needs a `tach`"));
+    }
+
+    // Without sensors in the config every fan uses the default one
+    if let (Some(wanted), false) = (&fan_config.sensors, config.sensors.is_empty()) {
+        let names: Vec<String> = (0..config.sensors.len())
+            .map(|i| sensor_name(config, i))
+            .collect();
+        if let Some(unknown) = wanted.iter().find(|name| !names.contains(name)) {
+            return Err(format!("unknown sensor {:?}", unknown));
+        }
+    }
+
+    // Fans sharing an output or tachometer would overwrite each other
+    let used = outputs(fan_config);
+    for (j, other) in fan_configs[..i].iter().enumerate() {
+        if let Some(output) = outputs(other)
+            .into_iter()
+            .find(|output| used.contains(output))
+        {
+            return Err(format!("shares {} with {}", output, other.name(j)));
+        }
+    }
+    Ok(())
+}
+
+fn check_fans(config: &Config, fan_configs: &[FanConfig]) -> Result<(), Error> {
+    for (i, fan_config) in fan_configs.iter().enumerate() {
+        check_fan(config, fan_configs, i)
+            .map_err(|err| Error::invalid(format!("{}: {}", fan_config.name(i), err)))?;
+    }
+    Ok(())
+}
+
+// The channel or pin a fan is driven through and its tachometer pin
+fn outputs(fan_config: &FanConfig) -> Vec<String> {
+    let output = match (fan_config.kind, fan_config.channel, fan_config.pin) {
+        (FanKind::HardwarePwm, Channel::Pwm0, _) => Some(String::from("channel pwm0")),
+        (FanKind::HardwarePwm, Channel::Pwm1, _) => Some(String::from("channel pwm1")),
+        (_, _, Some(pin)) => Some(format!("GPIO {}", pin)),
+        (_, _, None) => None, // reported when opening the fan
+    };
+    let tach = fan_config
+        .tach
+        .as_ref()
+        .map(|tach| format!("GPIO {}", tach.pin));
+    output.into_iter().chain(tach).collect()
+}
+
+// Whether a fan configured as `old_fan` in `old` reads its sensors the same
+// way as `new_fan` in `new`, so they can keep their filters' state
+fn same_sensors(old: &Config, new: &Config, old_fan: &FanConfig, new_fan: &FanConfig) -> bool {
+    let curves = |config: &Config| -> Vec<Option<RawCurve>> {
+        config
+            .sensors
+            .iter()
+            .map(|sensor| {
+                let name = sensor.curve.as_ref()?;
+                config.curves.get(name).cloned()
+            })
+            .collect()
+    };
+    old.sensors == new.sensors
+        && old.settings.aggregation == new.settings.aggregation
+        && old_fan.sensors == new_fan.sensors
+        && curves(old) == curves(new)
+}
+
+pub(crate) fn sensor_name(config: &Config, i: usize) -> String {
+    config.sensors[i]
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("sensor {}", i))
+}
+
+fn regulation(fan_config: &FanConfig) -> Result<Regulation, String> {
+    let invalid = |message: &str| Err(message.to_string());
+    if fan_config.off_temp.is_some() && fan_config.on_temp.is_none() {
+        return invalid("`off_temp` needs an `on_temp`");
+    }
+    if fan_config.on_temp.is_some() && fan_config.mode != FanMode::Duty {
+        return invalid("`on_temp` only works in `duty` mode");
+    }
+    // Following the curve would switch it at the curve's first non-zero
+    // point with no hysteresis at all
+    if fan_config.kind == FanKind::Switch && fan_config.on_temp.is_none() {
+        return invalid("switch fans need an `on_temp`");
+    }
+
+    let default = Default::default();
+    let pid_config = fan_config.pid.as_ref().unwrap_or(&default);
+    if fan_config.mode != FanMode::Duty {
+        let values = [
+            ("kp", pid_config.kp),
+            ("ki", pid_config.ki),
+            ("kd", pid_config.kd),
+            ("min_duty", Some(pid_config.min_duty)),
+            ("max_duty", Some(pid_config.max_duty)),
+            ("setpoint", pid_config.setpoint),
+            ("derivative_filter", Some(pid_config.derivative_filter)),
+        ];
+        if let Some((key, _)) = values
+            .iter()
+            .find(|(_, value)| value.is_some_and(|value| !value.is_finite()))
+        {
+            return invalid(&format!("`pid.{}` has to be a finite number", key));
+        }
+        if pid_config.min_duty > pid_config.max_duty {
+            return invalid(&format!(
+                "`pid.min_duty` {} is above `pid.max_duty` {}",
+                pid_config.min_duty, pid_config.max_duty
+            ));
+        }
+    }
+    let pid = |kp, ki, kd| {
+        Pid::new(
+            pid_config.kp.unwrap_or(kp),
+            pid_config.ki.unwrap_or(ki),
+            pid_config.kd.unwrap_or(kd),
+            pid_config.min_duty,
+            pid_config.max_duty,
+        )
+        .with_derivative_filter(pid_config.derivative_filter)
+    };
+
+    match fan_config.mode {
+        FanMode::Duty => match fan_config.on_temp {
+            Some(on_temp) => {
+                let off_temp = fan_config.off_temp.unwrap_or(on_temp - SWITCH_HYSTERESIS);
+                if off_temp > on_temp {
+                    return invalid(&format!(
+                        "`off_temp` {} is above `on_temp` {}",
+                        off_temp, on_temp
+                    ));
+                }
+                Ok(Regulation::Thermostat(Thermostat::new(on_temp, off_temp)))
+            }
+            None => Ok(Regulation::Curve),
+        },
+        FanMode::Rpm if fan_config.tach.is_none() => invalid("the `rpm` mode needs a `tach`"),
+        FanMode::Rpm => Ok(Regulation::Rpm(pid(pid::RPM_KP, pid::RPM_KI, 0.0))),
+        FanMode::Pid => match pid_config.setpoint {
+            Some(temp) => Ok(Regulation::Setpoint {
+                temp,
+                pid: pid(pid::TEMP_KP, pid::TEMP_KI, pid::TEMP_KD),
+            }),
+            None => invalid("the `pid` mode needs a `pid.setpoint`"),
+        },
+    }
+}
+
+fn smoothing(fan_config: &FanConfig) -> Result<(Option<Hysteresis>, Option<RampLimiter>), String> {
+    if fan_config.hysteresis < 0.0 {
+        return Err(String::from("`hysteresis` can't be negative"));
+    }
+    for rate in [fan_config.max_rise, fan_config.max_fall]
+        .into_iter()
+        .flatten()
+    {
+        if rate <= 0.0 {
+            return Err(format!("duty change limit {} %/s isn't positive", rate));
+        }
+    }
+
+    let hysteresis = (fan_config.hysteresis > 0.0).then(|| Hysteresis::new(fan_config.hysteresis));
+    let ramp = (fan_config.max_rise.is_some() || fan_config.max_fall.is_some())
+        .then(|| RampLimiter::new(fan_config.max_rise, fan_config.max_fall));
+    Ok((hysteresis, ramp))
+}
+
+/// Builds the curve called `name` in `config`, or `fan_curve` for `None`.
+pub fn named_curve(config: &Config, name: Option<&str>) -> Result<Curve, Error> {
+    let raw_curve = match name {
+        Some(name) => config
+            .curves
+            .get(name)
+            .ok_or_else(|| Error::invalid(format!("unknown curve {:?}", name)))?,
+        None => &config.fan_curve,
+    };
+    Curve::try_from(raw_curve)
+        .map_err(|err| Error::invalid(format!("{}: {}", name.unwrap_or("fan_curve"), err)))
+}
+
+fn open_sensors(
+    config: &Config,
+    fan_config: &FanConfig,
+    available: &[SensorInfo],
+) -> Result<SensorGroup, Error> {
+    if config.sensors.is_empty() {
+        let sensor = test_read("thermal_zone0", SysfsSensor::default())?;
+        return Ok(SensorGroup::single(sensor));
+    }
+
+    let names: Vec<String> = (0..config.sensors.len())
+        .map(|i| sensor_name(config, i))
+        .collect();
+    let mut sensors = SensorGroup::new(config.settings.aggregation);
+    for (sensor_config, name) in config.sensors.iter().zip(names) {
+        if let Some(wanted) = &fan_config.sensors {
+            if !wanted.contains(&name) {
+                continue;
+            }
+        }
+
+        let sensor =
+            sensor::resolve(sensor_config, available).map_err(|source| match source.kind() {
+                io::ErrorKind::InvalidInput => Error::invalid(format!("{}: {}", name, source)),
+                _ => Error::Sensor {
+                    name: name.clone(),
+                    source,
+                },
+            })?;
+        let sensor = test_read(&name, sensor)?;
+        let sensor: Box<dyn TemperatureSource> = match sensor_config.filter {
+            Some(filter) => Box::new(
+                Filtered::new(sensor, filter)
+                    .map_err(|err| Error::invalid(format!("{}: {}", name, err)))?,
+            ),
+            None => Box::new(sensor),
+        };
+        match &sensor_config.curve {
+            Some(_) => {
+                let curve = named_curve(config, sensor_config.curve.as_deref())?;
+                sensors.add_with_curve(&name, sensor, curve);
+            }
+            None => sensors.add(&name, sensor, sensor_config.weight),
+        }
+    }
+    Ok(sensors)
+}
+
+// Reads `sensor` once, so one that is missing or doesn't report a
+// temperature stops the daemon from starting rather than leaving the fan at
+// `FAIL_SPEED`
+fn test_read(name: &str, mut sensor: SysfsSensor) -> Result<SysfsSensor, Error> {
+    match sensor.read_temp() {
+        Ok(_) => Ok(sensor),
+        Err(source) => Err(Error::Sensor {
+            name: name.to_string(),
+            source,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{regulation, Controller, FanControl, Hardware};
+    use crate::config::{Config, FanConfig, TachConfig};
+    use crate::control::FAIL_SPEED;
+    use crate::curve::Curve;
+    use crate::error::EXIT_SENSOR;
+    use crate::fan::{Channel, FanOutput};
+    use crate::pid::Pid;
+    use crate::sensor::{Aggregation, SensorGroup};
+    use crate::sim::{MockFan, TraceSensor};
+    use crate::tach::RpmSource;
+    use crate::testing::scratch_dir;
+    use std::{fs, io};
+
+    #[derive(Default)]
+    struct FakeHardware {
+        channels: Vec<Channel>,
+    }
+
+    struct FakeTach(f32);
+
+    impl RpmSource for FakeTach {
+        fn read_rpm(&mut self) -> io::Result<f32> {
+            Ok(self.0)
+        }
+    }
+
+    impl Hardware for FakeHardware {
+        fn open_fan(&mut self, config: &FanConfig) -> io::Result<Box<dyn FanOutput>> {
+            self.channels.push(config.channel);
+            Ok(Box::new(MockFan::default()))
+        }
+
+        fn open_tach(&mut self, config: &TachConfig) -> io::Result<Box<dyn RpmSource>> {
+            Ok(Box::new(FakeTach(config.pin as f32 * 100.0)))
+        }
+    }
+
+    #[test]
+    fn fans_from_config() {
+        let dir = scratch_dir("controller");
+        fs::write(dir.join("soc"), "60000\n").unwrap();
+        fs::write(dir.join("nvme"), "45000\n").unwrap();
+
+        let config = format!(
+            r#"
+            [settings]
+            update_rate = 1.0
+
+            [fan_curve]
+            raw_curve = [[40, 0], [80, 100]]
+
+            [curves.nvme]
+            raw_curve = [[40, 0], [60, 100]]
+
+            [[sensors]]
+            name = "soc"
+            path = "{dir}/soc"
+
+            [[sensors]]
+            name = "nvme"
+            path = "{dir}/nvme"
+
+            [[fans]]
+            name = "intake"
+            sensors = ["soc"]
+
+            [[fans]]
+            name = "exhaust"
+            channel = "pwm1"
+            curve = "nvme"
+            tach = {{ pin = 24 }}
+            "#,
+            dir = dir.display()
+        );
+        let config: Config = toml::from_str(&config).unwrap();
+
+        let mut hardware = FakeHardware::default();
+        let mut controller = Controller::from_config(&config, &[], &mut hardware).unwrap();
+        assert_eq!(hardware.channels, vec![Channel::Pwm0, Channel::Pwm1]);
+
+        let speeds: Vec<f32> = controller
+            .update()
+            .into_iter()
+            .map(|tick| tick.unwrap().speed)
+            .collect();
+        assert_eq!(speeds, vec![50.0, 100.0]);
+        assert_eq!(
+            controller.status(),
+            "intake: 60.0°C, duty 50.0%\nexhaust: 60.0°C, duty 100.0%, 2400 rpm\n"
+        );
+    }
+
+    #[test]
+    fn reload_keeps_outputs() {
+        let dir = scratch_dir("reload-fans");
+        fs::write(dir.join("soc"), "60000\n").unwrap();
+        let config = |curve: &str, channel: &str| -> Config {
+            let config = format!(
+                r#"
+                [settings]
+                update_rate = 1.0
+
+                [fan_curve]
+                raw_curve = {curve}
+
+                [[sensors]]
+                path = "{dir}/soc"
+
+                [[fans]]
+                channel = "{channel}"
+                "#,
+                dir = dir.display()
+            );
+            toml::from_str(&config).unwrap()
+        };
+        let speed = |controller: &mut Controller| controller.update()[0].as_ref().unwrap().speed;
+
+        let mut hardware = FakeHardware::default();
+        let mut controller =
+            Controller::from_config(&config("[[40, 0], [80, 100]]", "pwm0"), &[], &mut hardware)
+                .unwrap();
+        assert_eq!(speed(&mut controller), 50.0);
+
+        controller
+            .reload(&config("[[40, 0], [60, 100]]", "pwm0"), &[])
+            .unwrap();
+        assert_eq!(speed(&mut controller), 100.0);
+        assert_eq!(hardware.channels, vec![Channel::Pwm0]);
+
+        // Invalid configs and rewired fans leave everything as it was
+        assert!(controller.reload(&config("[]", "pwm0"), &[]).is_err());
+        assert!(controller
+            .reload(&config("[[40, 0], [80, 100]]", "pwm1"), &[])
+            .is_err());
+        assert_eq!(speed(&mut controller), 100.0);
+
+        // The PID loop of a fan in `rpm` mode carries on as if there had
+        // been no reload
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [settings]
+            update_rate = 1.0
+
+            [fan_curve]
+            raw_curve = [[40, 1000], [80, 3000]]
+
+            [[sensors]]
+            path = "{dir}/soc"
+
+            [[fans]]
+            mode = "rpm"
+            tach = {{ pin = 10 }}
+            "#,
+            dir = dir.display()
+        ))
+        .unwrap();
+        let mut reloaded = Controller::from_config(&config, &[], &mut hardware).unwrap();
+        let mut untouched = Controller::from_config(&config, &[], &mut hardware).unwrap();
+        for _ in 0..3 {
+            speed(&mut reloaded);
+            speed(&mut untouched);
+        }
+        reloaded.reload(&config, &[]).unwrap();
+        let duty = speed(&mut untouched);
+        assert!(duty > 60.0);
+        assert_eq!(speed(&mut reloaded), duty);
+    }
+
+    #[test]
+    fn shared_outputs() {
+        let dir = scratch_dir("shared-outputs");
+        fs::write(dir.join("soc"), "60000\n").unwrap();
+        let error = |fans: &str| {
+            let config = format!(
+                "[settings]\nupdate_rate = 1.0\n[fan_curve]\nraw_curve = [[40, 0], [80, 100]]\n\
+                 [[sensors]]\npath = \"{}/soc\"\n{}",
+                dir.display(),
+                fans
+            );
+            let config: Config = toml::from_str(&config).unwrap();
+            Controller::from_config(&config, &[], &mut FakeHardware::default())
+                .err()
+                .map(|err| err.to_string())
+        };
+
+        assert_eq!(
+            error("[[fans]]\nname = \"a\"\n[[fans]]\nname = \"b\""),
+            Some(String::from(
+                "invalid config: b: shares channel pwm0 with a"
+            ))
+        );
+        assert_eq!(
+            error(
+                "[[fans]]\ntype = \"switch\"\npin = 17\non_temp = 60\n\
+                 [[fans]]\ntach = { pin = 17 }"
+            ),
+            Some(String::from(
+                "invalid config: fan 1: shares GPIO 17 with fan 0"
+            ))
+        );
+        assert_eq!(error("[[fans]]\n[[fans]]\nchannel = \"pwm1\""), None);
+    }
+
+    #[test]
+    fn unreadable_sensors() {
+        let dir = scratch_dir("unreadable");
+        fs::write(dir.join("garbage"), "hot\n").unwrap();
+        let error = |path: &str| {
+            let config = format!(
+                "[settings]\nupdate_rate = 1.0\n[fan_curve]\nraw_curve = [[40, 0], [80, 100]]\n\
+                 [[sensors]]\nname = \"soc\"\npath = \"{}/{}\"",
+                dir.display(),
+                path
+            );
+            let config: Config = toml::from_str(&config).unwrap();
+            Controller::from_config(&config, &[], &mut FakeHardware::default()).err()
+        };
+
+        for path in ["missing", "garbage"] {
+            let err = error(path).unwrap();
+            assert_eq!(err.exit_code(), EXIT_SENSOR);
+            assert!(err.to_string().starts_with("failed to read soc: "));
+        }
+    }
+
+    #[test]
+    fn switch_thresholds() {
+        let fan_config = |config: &str| -> FanConfig { toml::from_str(config).unwrap() };
+        assert!(regulation(&fan_config("type = \"switch\"\npin = 14\non_temp = 60")).is_ok());
+        assert_eq!(
+            regulation(&fan_config("type = \"switch\"\npin = 14")).err(),
+            Some(String::from("switch fans need an `on_temp`"))
+        );
+    }
+
+    #[test]
+    fn pid_limits() {
+        let fan_config = |config: &str| -> FanConfig { toml::from_str(config).unwrap() };
+        assert_eq!(
+            regulation(&fan_config(
+                "mode = \"pid\"\npid = { setpoint = 50, min_duty = 80, max_duty = 50 }"
+            ))
+            .err(),
+            Some(String::from("`pid.min_duty` 80 is above `pid.max_duty` 50"))
+        );
+        assert_eq!(
+            regulation(&fan_config(
+                "mode = \"rpm\"\ntach = { pin = 24 }\npid = { max_duty = nan }"
+            ))
+            .err(),
+            Some(String::from("`pid.max_duty` has to be a finite number"))
+        );
+        // Duty fans don't use the PID settings
+        assert!(regulation(&fan_config("pid = { min_duty = 80, max_duty = 50 }")).is_ok());
+    }
+
+    #[test]
+    fn rpm_target() {
+        let sensors = SensorGroup::single(TraceSensor::new(vec![50.0, 50.0]));
+        let curve = Curve::new(vec![(40.0, 1000.0), (60.0, 2000.0)]).unwrap();
+        let mut fan = FanControl::new("fan", sensors, curve, MockFan::default())
+            .with_tach(FakeTach(1000.0))
+            .with_rpm_control(Pid::new(0.0, 0.01, 0.0, 0.0, 100.0));
+
+        let tick = fan.update(1.0).unwrap();
+        assert_eq!(tick.target_rpm, Some(1500.0));
+        assert_eq!(tick.speed, 5.0);
+        assert_eq!(fan.update(1.0).unwrap().speed, 10.0);
+    }
+
+    #[test]
+    fn rpm_failed_sensor() {
+        let curve = Curve::new(vec![(40.0, 1000.0), (60.0, 2000.0)]).unwrap();
+        let mut sensors = SensorGroup::new(Aggregation::Max);
+        sensors.add("soc", TraceSensor::new(vec![50.0]), 1.0);
+        sensors.add_with_curve("nvme", TraceSensor::new(vec![]), curve.clone());
+        let mut fan = FanControl::new("fan", sensors, curve, MockFan::default())
+            .with_tach(FakeTach(1000.0))
+            .with_rpm_control(Pid::new(0.0, 0.01, 0.0, 0.0, 100.0));
+
+        let tick = fan.update(1.0).unwrap();
+        assert!(tick.sensor_failed);
+        assert_eq!(tick.target_rpm, None);
+        assert_eq!(tick.speed, FAIL_SPEED);
+    }
+
+    #[test]
+    fn temperature_setpoint() {
+        let sensors = SensorGroup::single(TraceSensor::new(vec![50.0, 55.0]));
+        let curve = Curve::new(vec![(0.0, 100.0)]).unwrap();
+        let mut fan = FanControl::new("fan", sensors, curve, MockFan::default())
+            .with_setpoint(45.0, Pid::new(4.0, 0.0, 0.0, 0.0, 100.0));
+
+        assert_eq!(fan.update(1.0).unwrap().speed, 20.0);
+        assert_eq!(fan.update(1.0).unwrap().speed, 40.0);
+        // Out of readings
+        assert_eq!(fan.update(1.0).unwrap().speed, FAIL_SPEED);
+    }
+}
@@ -0,0 +1,291 @@
+use serde::Deserialize;
+use std::{error, fmt};
+
+/// How a curve fills in the speed between its points.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    Step,       // hold the previous point's speed until the next point
+    Pchip,      // monotone cubic Hermite, smooth without overshooting the points
+    Smoothstep, // eases in and out of every point
+}
+
+/// Why a list of points doesn't make a curve.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurveError {
+    Empty,
+    NotFinite(f32, f32), // a point with a NaN or infinite coordinate
+    Duplicate(f32),      // two points at the same temperature
+    OutOfOrder { before: f32, after: f32 }, // temperatures have to increase
+}
+
+impl fmt::Display for CurveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CurveError::Empty => write!(f, "the curve has no points"),
+            CurveError::NotFinite(temp, speed) => {
+                write!(f, "point [{}, {}] isn't a finite number", temp, speed)
+            }
+            CurveError::Duplicate(temp) => write!(f, "more than one point at {}°C", temp),
+            CurveError::OutOfOrder { before, after } => write!(
+                f,
+                "point at {}°C comes after {}°C, temperatures have to increase",
+                after, before
+            ),
+        }
+    }
+}
+
+impl error::Error for CurveError {}
+
+/// Fan curve mapping temperature (°C) to fan speed (%), linearly
+/// interpolated between points unless told otherwise.
+///
+/// Points are kept in order of strictly increasing temperature, which `new`
+/// checks, so looking up a temperature is a binary search.
+#[derive(Debug, Clone)]
+pub struct Curve {
+    points: Vec<(f32, f32)>, // sorted by temperature
+    tangents: Vec<f32>,      // slope at each point for `Pchip`
+    interpolation: Interpolation,
+}
+
+impl TryFrom<Vec<(f32, f32)>> for Curve {
+    type Error = CurveError;
+
+    fn try_from(points: Vec<(f32, f32)>) -> Result<Self, Self::Error> {
+        Curve::new(points)
+    }
+}
+
+impl Curve {
+    /// Builds a curve from `(temperature, speed)` points given in order of
+    /// increasing temperature.
+    pub fn new(points: Vec<(f32, f32)>) -> Result<Self, CurveError> {
+        if points.is_empty() {
+            return Err(CurveError::Empty);
+        }
+        if let Some(&(temp, speed)) = points
+            .iter()
+            .find(|(temp, speed)| !temp.is_finite() || !speed.is_finite())
+        {
+            return Err(CurveError::NotFinite(temp, speed));
+        }
+        for pair in points.windows(2) {
+            let (before, after) = (pair[0].0, pair[1].0);
+            if before == after {
+                return Err(CurveError::Duplicate(after));
+            }
+            if before > after {
+                return Err(CurveError::OutOfOrder { before, after });
+            }
+        }
+
+        Ok(Curve {
+            tangents: pchip_tangents(&points),
+            points,
+            interpolation: Interpolation::default(),
+        })
+    }
+
+    pub fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    /// The points, sorted by temperature.
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    /// Returns the fan speed for `temp`, interpolating between the
+    /// surrounding points and holding the first and last point's speed
+    /// outside of them.
+    pub fn get_value_at(&self, temp: f32) -> f32 {
+        // Number of points at or below `temp`
+        let below = self.points.partition_point(|point| point.0 <= temp);
+        if below == 0 {
+            self.points[0].1
+        } else if below == self.points.len() {
+            self.points[below - 1].1
+        } else {
+            self.get_value_between_points(below - 1, temp)
+        }
+    }
+
+    // Interpolates between point `i` and the next one
+    fn get_value_between_points(&self, i: usize, temp: f32) -> f32 {
+        let (x1, y1) = self.points[i];
+        let (x2, y2) = self.points[i + 1];
+        let width = x2 - x1;
+        let t = (temp - x1) / width;
+
+        match self.interpolation {
+            Interpolation::Linear => (y2 - y1) / width * (temp - x1) + y1,
+            Interpolation::Step => y1,
+            Interpolation::Smoothstep => y1 + (y2 - y1) * t * t * (3.0 - 2.0 * t),
+            Interpolation::Pchip => {
+                let (t2, t3) = (t * t, t * t * t);
+                (2.0 * t3 - 3.0 * t2 + 1.0) * y1
+                    + (t3 - 2.0 * t2 + t) * width * self.tangents[i]
+                    + (-2.0 * t3 + 3.0 * t2) * y2
+                    + (t3 - t2) * width * self.tangents[i + 1]
+            }
+        }
+    }
+}
+
+// Fritsch-Carlson tangents, which keep the cubic between two points within
+// their speeds so a monotonic curve stays monotonic.
+fn pchip_tangents(points: &[(f32, f32)]) -> Vec<f32> {
+    let widths: Vec<f32> = points
+        .windows(2)
+        .map(|pair| pair[1].0 - pair[0].0)
+        .collect();
+    let slopes: Vec<f32> = points
+        .windows(2)
+        .zip(&widths)
+        .map(|(pair, width)| (pair[1].1 - pair[0].1) / width)
+        .collect();
+
+    (0..points.len())
+        .map(|i| match (i.checked_sub(1), slopes.get(i)) {
+            (None, Some(&slope)) => slope,
+            (Some(before), None) => slopes.get(before).copied().unwrap_or(0.0),
+            (Some(before), Some(&after)) => {
+                let before_slope = slopes[before];
+                if before_slope * after <= 0.0 {
+                    // A peak, valley or flat stretch
+                    0.0
+                } else {
+                    let w1 = 2.0 * widths[i] + widths[before];
+                    let w2 = widths[i] + 2.0 * widths[before];
+                    (w1 + w2) / (w1 / before_slope + w2 / after)
+                }
+            }
+            (None, None) => 0.0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn basic_speed() {
+        let curve = vec![
+            (0.0, 0.0),
+            (10.0, 100.0),
+            (20.0, 200.0),
+            (30.0, 300.0),
+            (40.0, 400.0),
+            (50.0, 500.0),
+        ];
+        let curve = super::Curve::new(curve).unwrap();
+        assert_eq!(curve.get_value_at(0.0), 0.0);
+        assert_eq!(curve.get_value_at(10.0), 100.0);
+        assert_eq!(curve.get_value_at(20.0), 200.0);
+        assert_eq!(curve.get_value_at(30.0), 300.0);
+        assert_eq!(curve.get_value_at(40.0), 400.0);
+        assert_eq!(curve.get_value_at(50.0), 500.0);
+    }
+
+    #[test]
+    fn linear_speed() {
+        let curve = vec![
+            (0.0, 0.0),
+            (10.0, 100.0),
+            (20.0, 200.0),
+            (30.0, 300.0),
+            (40.0, 400.0),
+            (50.0, 500.0),
+        ];
+        let curve = super::Curve::new(curve).unwrap();
+        assert_eq!(curve.get_value_at(5.0), 50.0);
+        assert_eq!(curve.get_value_at(15.0), 150.0);
+        assert_eq!(curve.get_value_at(25.0), 250.0);
+        assert_eq!(curve.get_value_at(35.0), 350.0);
+        assert_eq!(curve.get_value_at(45.0), 450.0);
+    }
+    #[test]
+    fn quadratic_speed() {
+        let curve = vec![(0.0, 0.0), (10.0, 100.0), (20.0, 300.0), (30.0, 700.0)];
+        let curve = super::Curve::new(curve).unwrap();
+        assert_eq!(curve.get_value_at(5.0), 50.0);
+        assert_eq!(curve.get_value_at(15.0), 200.0);
+        assert_eq!(curve.get_value_at(25.0), 500.0);
+    }
+
+    #[test]
+    fn fractional_points() {
+        let raw: crate::config::RawCurve =
+            toml::from_str("raw_curve = [[40, 0], [62.5, 45.0], [75.5, 100]]").unwrap();
+        let curve = super::Curve::new(raw.raw_curve).unwrap();
+        assert_eq!(curve.get_value_at(51.25), 22.5);
+        assert_eq!(curve.get_value_at(62.5), 45.0);
+        assert_eq!(curve.get_value_at(69.0), 72.5);
+    }
+
+    #[test]
+    fn interpolation() {
+        use super::{Curve, Interpolation};
+
+        let points = vec![(40.0, 0.0), (50.0, 20.0), (60.0, 80.0), (70.0, 100.0)];
+        let curve = |interpolation| {
+            Curve::new(points.clone())
+                .unwrap()
+                .with_interpolation(interpolation)
+        };
+
+        assert_eq!(curve(Interpolation::Step).get_value_at(55.0), 20.0);
+        assert_eq!(curve(Interpolation::Smoothstep).get_value_at(55.0), 50.0);
+        assert_eq!(curve(Interpolation::Smoothstep).get_value_at(52.5), 29.375);
+        for interpolation in [Interpolation::Pchip, Interpolation::Smoothstep] {
+            let curve = curve(interpolation);
+            assert_eq!(curve.get_value_at(60.0), 80.0);
+            // Never decreasing and never past the points around it
+            let speeds: Vec<f32> = (400..=700)
+                .map(|temp| curve.get_value_at(temp as f32 / 10.0))
+                .collect();
+            assert!(speeds.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert!(speeds.iter().all(|&speed| (0.0..=100.0).contains(&speed)));
+        }
+    }
+
+    #[test]
+    fn clamps_outside_points() {
+        let curve = super::Curve::new(vec![(40.0, 20.0), (60.0, 100.0)]).unwrap();
+        assert_eq!(curve.get_value_at(-10.0), 20.0);
+        assert_eq!(curve.get_value_at(90.0), 100.0);
+        let curve = super::Curve::new(vec![(50.0, 30.0)]).unwrap();
+        assert_eq!(curve.get_value_at(40.0), 30.0);
+        assert_eq!(curve.get_value_at(60.0), 30.0);
+    }
+
+    #[test]
+    fn invalid_curves() {
+        use super::{Curve, CurveError};
+
+        assert_eq!(Curve::new(vec![]).unwrap_err(), CurveError::Empty);
+        assert_eq!(
+            Curve::new(vec![(40.0, 0.0), (50.0, 50.0), (50.0, 60.0)]).unwrap_err(),
+            CurveError::Duplicate(50.0)
+        );
+        assert_eq!(
+            Curve::new(vec![(40.0, 0.0), (70.0, 100.0), (60.0, 80.0)]).unwrap_err(),
+            CurveError::OutOfOrder {
+                before: 70.0,
+                after: 60.0
+            }
+        );
+        assert!(matches!(
+            Curve::new(vec![(f32::NAN, 0.0)]),
+            Err(CurveError::NotFinite(..))
+        ));
+    }
+}
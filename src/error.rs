@@ -0,0 +1,142 @@
+//! Errors that stop the daemon from starting, with enough context to fix
+//! them and an exit code telling them apart.
+
+use crate::fan::FanKind;
+use std::path::PathBuf;
+use std::{error, fmt, io};
+
+// Exit codes of the `pi-fan` binary
+pub const EXIT_CONFIG: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_STALLED: i32 = 3;
+pub const EXIT_SENSOR: i32 = 4;
+pub const EXIT_HARDWARE: i32 = 5;
+
+const PWM_HINT: &str = "the PWM channels need `dtoverlay=pwm` (or `dtoverlay=pwm-2chan` \
+for both) in /boot/config.txt, followed by a reboot";
+const PERMISSION_HINT: &str = "pi-fan needs to run as root or as a user in the `gpio` group";
+
+#[derive(Debug)]
+pub enum Error {
+    ReadConfig {
+        path: PathBuf,
+        source: io::Error,
+    },
+    ParseConfig {
+        path: PathBuf,
+        source: toml::de::Error, // its message carries the line and column
+    },
+    InvalidConfig(String), // parses, but doesn't describe a working setup
+    Sensor {
+        name: String,
+        source: io::Error,
+    },
+    Fan {
+        name: String,
+        kind: FanKind,
+        source: io::Error,
+    },
+    Tach {
+        name: String,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub fn invalid<S: Into<String>>(message: S) -> Self {
+        Error::InvalidConfig(message.into())
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ReadConfig { .. } | Error::ParseConfig { .. } | Error::InvalidConfig(_) => {
+                EXIT_CONFIG
+            }
+            Error::Sensor { .. } => EXIT_SENSOR,
+            Error::Fan { .. } | Error::Tach { .. } => EXIT_HARDWARE,
+        }
+    }
+
+    // What is most likely wrong when hardware can't be opened
+    fn hint(&self) -> Option<&'static str> {
+        let (kind, source) = match self {
+            Error::Fan { kind, source, .. } => (Some(*kind), source),
+            Error::Tach { source, .. } => (None, source),
+            _ => return None,
+        };
+        match source.kind() {
+            io::ErrorKind::PermissionDenied => Some(PERMISSION_HINT),
+            io::ErrorKind::NotFound if kind == Some(FanKind::HardwarePwm) => Some(PWM_HINT),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ReadConfig { path, source } => {
+                write!(f, "failed to read {}: {}", path.display(), source)?
+            }
+            Error::ParseConfig { path, source } => {
+                write!(f, "invalid config {}: {}", path.display(), source)?
+            }
+            Error::InvalidConfig(message) => write!(f, "invalid config: {}", message)?,
+            Error::Sensor { name, source } => write!(f, "failed to read {}: {}", name, source)?,
+            Error::Fan { name, source, .. } => write!(f, "failed to open {}: {}", name, source)?,
+            Error::Tach { name, source } => {
+                write!(f, "failed to open tachometer of {}: {}", name, source)?
+            }
+        }
+        if let Some(hint) = self.hint() {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::ReadConfig { source, .. }
+            | Error::Sensor { source, .. }
+            | Error::Fan { source, .. }
+            | Error::Tach { source, .. } => Some(source),
+            Error::ParseConfig { source, .. } => Some(source),
+            Error::InvalidConfig(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, EXIT_CONFIG, EXIT_HARDWARE};
+    use crate::config::Config;
+    use crate::fan::FanKind;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[test]
+    fn messages() {
+        let source = toml::from_str::<Config>("[settings]\nupdate_rate = \"fast\"").err();
+        let err = Error::ParseConfig {
+            path: PathBuf::from("/etc/pi-fan.toml"),
+            source: source.unwrap(),
+        };
+        assert_eq!(err.exit_code(), EXIT_CONFIG);
+        assert!(err
+            .to_string()
+            .starts_with("invalid config /etc/pi-fan.toml: invalid type"));
+        assert!(err.to_string().contains("line 2 column"));
+
+        let err = Error::Fan {
+            name: String::from("fan 0"),
+            kind: FanKind::HardwarePwm,
+            source: io::Error::new(io::ErrorKind::NotFound, "No such file or directory"),
+        };
+        assert_eq!(err.exit_code(), EXIT_HARDWARE);
+        assert!(err
+            .to_string()
+            .contains("\nhint: the PWM channels need `dtoverlay=pwm`"));
+    }
+}
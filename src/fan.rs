@@ -0,0 +1,203 @@
+use crate::config::FanConfig;
+use rppal::gpio::{self, Gpio, OutputPin};
+use rppal::pwm;
+use serde::Deserialize;
+use std::io;
+
+pub const PWM_FREQUENCY: f64 = 25000.0; // Hz, as specified for 4-pin PC fans
+pub const SOFT_PWM_FREQUENCY: f64 = 100.0; // Hz, low enough to keep jitter in check
+
+/// Anything that can drive a fan at a speed given in percent.
+pub trait FanOutput {
+    fn set_speed(&mut self, speed: f32) -> io::Result<()>;
+}
+
+impl FanOutput for pwm::Pwm {
+    fn set_speed(&mut self, speed: f32) -> io::Result<()> {
+        let duty_cycle = (speed as f64 / 100.0).clamp(0.0, 1.0);
+        self.set_duty_cycle(duty_cycle).map_err(pwm_error)
+    }
+}
+
+fn pwm_error(err: pwm::Error) -> io::Error {
+    match err {
+        pwm::Error::Io(err) => err,
+    }
+}
+
+fn gpio_error(err: gpio::Error) -> io::Error {
+    match err {
+        gpio::Error::Io(err) => err,
+        gpio::Error::PermissionDenied(_) => io::Error::new(io::ErrorKind::PermissionDenied, err),
+        err => io::Error::other(err),
+    }
+}
+
+/// Software PWM on an arbitrary GPIO pin, for when the hardware channels are
+/// taken by analog audio or a HAT.
+///
+/// The signal is generated by a background thread toggling the pin, so its
+/// timing is subject to scheduler jitter: expect pulse widths to be off by
+/// tens of microseconds, and more under load. That is fine for a 3-pin fan
+/// switched through a transistor at around 100 Hz, but 4-pin fans expecting
+/// a steady 25 kHz signal will not be driven reliably, and the pulsing may be
+/// audible at low frequencies.
+pub struct SoftPwm {
+    pin: OutputPin,
+    frequency: f64,
+    polarity: Polarity,
+}
+
+impl SoftPwm {
+    pub fn new(pin: u8, frequency: f64, polarity: Polarity) -> gpio::Result<Self> {
+        let mut soft_pwm = SoftPwm {
+            pin: Gpio::new()?.get(pin)?.into_output_low(),
+            frequency,
+            polarity,
+        };
+        soft_pwm.set_duty_cycle(0.0)?;
+        Ok(soft_pwm)
+    }
+
+    fn set_duty_cycle(&mut self, duty_cycle: f64) -> gpio::Result<()> {
+        let duty_cycle = match self.polarity {
+            Polarity::Normal => duty_cycle,
+            Polarity::Inverse => 1.0 - duty_cycle,
+        };
+        self.pin.set_pwm_frequency(self.frequency, duty_cycle)
+    }
+}
+
+impl FanOutput for SoftPwm {
+    fn set_speed(&mut self, speed: f32) -> io::Result<()> {
+        let duty_cycle = (speed as f64 / 100.0).clamp(0.0, 1.0);
+        self.set_duty_cycle(duty_cycle).map_err(gpio_error)
+    }
+}
+
+/// A 2-pin fan switched fully on or off through a transistor on a GPIO pin.
+/// Any speed above 0% turns it on.
+pub struct Switch {
+    pin: OutputPin,
+    polarity: Polarity,
+}
+
+impl Switch {
+    pub fn new(pin: u8, polarity: Polarity) -> gpio::Result<Self> {
+        let mut switch = Switch {
+            pin: Gpio::new()?.get(pin)?.into_output(),
+            polarity,
+        };
+        switch.set_on(false);
+        Ok(switch)
+    }
+
+    fn set_on(&mut self, on: bool) {
+        let high = match self.polarity {
+            Polarity::Normal => on,
+            Polarity::Inverse => !on,
+        };
+        if high {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+    }
+}
+
+impl FanOutput for Switch {
+    fn set_speed(&mut self, speed: f32) -> io::Result<()> {
+        self.set_on(speed > 0.0);
+        Ok(())
+    }
+}
+
+/// How a fan is connected.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum FanKind {
+    #[default]
+    HardwarePwm, // one of the PWM channels
+    SoftwarePwm, // any GPIO pin, see `SoftPwm` for the caveats
+    Switch,      // plain on/off GPIO pin
+}
+
+/// The Pi's hardware PWM channels.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Channel {
+    #[default]
+    Pwm0, // GPIO 18 or 12
+    Pwm1, // GPIO 19 or 13
+}
+
+impl From<Channel> for pwm::Channel {
+    fn from(channel: Channel) -> Self {
+        match channel {
+            Channel::Pwm0 => pwm::Channel::Pwm0,
+            Channel::Pwm1 => pwm::Channel::Pwm1,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Polarity {
+    #[default]
+    Normal,
+    Inverse,
+}
+
+impl From<Polarity> for pwm::Polarity {
+    fn from(polarity: Polarity) -> Self {
+        match polarity {
+            Polarity::Normal => pwm::Polarity::Normal,
+            Polarity::Inverse => pwm::Polarity::Inverse,
+        }
+    }
+}
+
+/// Opens a hardware PWM channel for a fan, starting at 0% duty.
+pub fn open_pwm(channel: Channel, frequency: f64, polarity: Polarity) -> pwm::Result<pwm::Pwm> {
+    pwm::Pwm::with_frequency(channel.into(), frequency, 0.0, polarity.into(), true)
+}
+
+/// How a fan's duty cycle is chosen.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum FanMode {
+    #[default]
+    Duty, // the curve gives the duty cycle in percent
+    Rpm, // the curve gives target RPM, reached by a PI loop on tachometer feedback
+    Pid, // no curve, a PID loop holds the temperature at `pid.setpoint`
+}
+
+/// Opens the output described by a fan's config.
+pub fn open(config: &FanConfig) -> io::Result<Box<dyn FanOutput>> {
+    match config.kind {
+        FanKind::HardwarePwm => {
+            let frequency = config.frequency.unwrap_or(PWM_FREQUENCY);
+            let pwm = open_pwm(config.channel, frequency, config.polarity).map_err(pwm_error)?;
+            Ok(Box::new(pwm))
+        }
+        FanKind::SoftwarePwm => {
+            let frequency = config.frequency.unwrap_or(SOFT_PWM_FREQUENCY);
+            let soft_pwm =
+                SoftPwm::new(gpio_pin(config)?, frequency, config.polarity).map_err(gpio_error)?;
+            Ok(Box::new(soft_pwm))
+        }
+        FanKind::Switch => {
+            let switch = Switch::new(gpio_pin(config)?, config.polarity).map_err(gpio_error)?;
+            Ok(Box::new(switch))
+        }
+    }
+}
+
+fn gpio_pin(config: &FanConfig) -> io::Result<u8> {
+    config.pin.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "software PWM and switch fans need a `pin`",
+        )
+    })
+}
@@ -0,0 +1,148 @@
+//! Smoothing filters for temperature readings, so short bursts of load don't
+//! spin the fan up and down.
+
+use crate::sensor::TemperatureSource;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io;
+
+/// How a sensor's readings are smoothed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", deny_unknown_fields, rename_all = "kebab-case")]
+pub enum Filter {
+    MovingAverage { samples: usize }, // mean of the last `samples` readings
+    Ema { alpha: f32 },               // weight of each new reading, in (0, 1]
+    Median { samples: usize },        // median of the last `samples`, rejects spikes
+}
+
+/// Wraps a sensor and filters its readings. Failed readings are passed
+/// through without touching the filter's state.
+pub struct Filtered<S> {
+    source: S,
+    filter: Filter,
+    window: VecDeque<f32>,
+    average: Option<f32>, // the EMA so far
+}
+
+impl Filter {
+    /// Checks the filter's parameters.
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            Filter::MovingAverage { samples: 0 } | Filter::Median { samples: 0 } => {
+                Err(String::from("the filter's `samples` has to be at least 1"))
+            }
+            Filter::Ema { alpha } if !(alpha > 0.0 && alpha <= 1.0) => Err(format!(
+                "the filter's `alpha` has to be in (0, 1], not {}",
+                alpha
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: TemperatureSource> Filtered<S> {
+    pub fn new(source: S, filter: Filter) -> io::Result<Self> {
+        filter
+            .check()
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidInput, message))?;
+        Ok(Filtered {
+            source,
+            filter,
+            window: VecDeque::new(),
+            average: None,
+        })
+    }
+
+    fn push(&mut self, samples: usize, temp: f32) {
+        if self.window.len() == samples {
+            self.window.pop_front();
+        }
+        self.window.push_back(temp);
+    }
+}
+
+impl<S: TemperatureSource> TemperatureSource for Filtered<S> {
+    fn read_temp(&mut self) -> io::Result<f32> {
+        let temp = self.source.read_temp()?;
+        let filtered = match self.filter {
+            Filter::MovingAverage { samples } => {
+                self.push(samples, temp);
+                self.window.iter().sum::<f32>() / self.window.len() as f32
+            }
+            Filter::Ema { alpha } => {
+                let average = match self.average {
+                    Some(average) => average + alpha * (temp - average),
+                    None => temp,
+                };
+                self.average = Some(average);
+                average
+            }
+            Filter::Median { samples } => {
+                self.push(samples, temp);
+                let mut sorted: Vec<f32> = self.window.iter().copied().collect();
+                sorted.sort_unstable_by(f32::total_cmp);
+                let middle = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[middle - 1] + sorted[middle]) / 2.0
+                } else {
+                    sorted[middle]
+                }
+            }
+        };
+        Ok(filtered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, Filtered};
+    use crate::sensor::TemperatureSource;
+    use crate::sim::TraceSensor;
+
+    fn filter(filter: Filter, temps: Vec<f32>) -> Vec<f32> {
+        let count = temps.len();
+        let mut sensor = Filtered::new(TraceSensor::new(temps), filter).unwrap();
+        (0..count).map(|_| sensor.read_temp().unwrap()).collect()
+    }
+
+    #[test]
+    fn filters() {
+        let temps = vec![40.0, 40.0, 70.0, 40.0, 40.0, 46.0];
+        assert_eq!(
+            filter(Filter::MovingAverage { samples: 3 }, temps.clone()),
+            vec![40.0, 40.0, 50.0, 50.0, 50.0, 42.0]
+        );
+        assert_eq!(
+            filter(Filter::Ema { alpha: 0.5 }, temps.clone()),
+            vec![40.0, 40.0, 55.0, 47.5, 43.75, 44.875]
+        );
+        assert_eq!(
+            filter(Filter::Median { samples: 3 }, temps),
+            vec![40.0, 40.0, 40.0, 40.0, 40.0, 40.0]
+        );
+    }
+
+    #[test]
+    fn invalid_filters() {
+        assert!(Filtered::new(TraceSensor::new(vec![]), Filter::Median { samples: 0 }).is_err());
+        assert!(Filtered::new(TraceSensor::new(vec![]), Filter::Ema { alpha: 1.5 }).is_err());
+        assert_eq!(
+            Filter::Ema { alpha: f32::NAN }.check(),
+            Err(String::from(
+                "the filter's `alpha` has to be in (0, 1], not NaN"
+            ))
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let parse = |text: &str| toml::from_str::<Filter>(text).map_err(|err| err.to_string());
+        assert_eq!(
+            parse("type = \"median\"\nsamples = 5"),
+            Ok(Filter::Median { samples: 5 })
+        );
+        assert!(parse("type = \"median\"\nsmaples = 5")
+            .unwrap_err()
+            .starts_with("unknown field `smaples`"));
+    }
+}
@@ -0,0 +1,325 @@
+//! The effective config, built from the main config file, the drop-ins next
+//! to it and `PI_FAN_*` environment variables.
+//!
+//! Drop-ins are the `*.toml` files in the directory named after the config
+//! file, `/etc/pi-fan.d` for `/etc/pi-fan.toml`, applied in lexical order.
+//! Tables are merged key by key, anything else, including arrays such as
+//! `[[fans]]`, replaces what came before.
+
+use crate::config::{self, Config};
+use crate::error::Error;
+use serde::de;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use toml::Value;
+
+pub const ENV_PREFIX: &str = "PI_FAN_";
+
+// Settings that can be overridden from the environment, e.g. `update_rate`
+// with `PI_FAN_UPDATE_RATE`
+const ENV_SETTINGS: &[&str] = &[
+    "update_rate",
+    "aggregation",
+    "status_file",
+    "log_interval",
+    "watch_config",
+];
+
+// Passed to the stall `command`, which may well run pi-fan again
+const HOOK_VARS: &[&str] = &["PI_FAN_FAN", "PI_FAN_DUTY", "PI_FAN_RPM"];
+
+/// Every layer of the config merged into one TOML document.
+pub struct Layers {
+    path: PathBuf,
+    value: Value,
+    text: Option<String>, // the main file's text when nothing else was merged in
+    layers: Vec<(Source, Value)>, // each layer on its own, to tell which one broke the config
+}
+
+// Where a layer came from
+enum Source {
+    File(PathBuf),
+    Var(String),
+}
+
+impl Source {
+    fn error(&self, source: toml::de::Error) -> Error {
+        match self {
+            Source::File(path) => Error::ParseConfig {
+                path: path.clone(),
+                source,
+            },
+            Source::Var(var) => Error::invalid(format!("{}: {}", var, source)),
+        }
+    }
+}
+
+impl Layers {
+    /// Reads the config file at `path` and its drop-ins, then applies the
+    /// overrides from `vars`, normally `std::env::vars()`.
+    pub fn read<I: IntoIterator<Item = (String, String)>>(
+        path: &Path,
+        vars: I,
+    ) -> Result<Self, Error> {
+        let text = read(path)?;
+        let mut value = parse(path, &text)?;
+        // Drop-ins follow the main file's layout
+        let version = config::version_of(&value);
+        migrate(path, &mut value, version)?;
+        let mut layers = vec![(Source::File(path.to_path_buf()), value.clone())];
+
+        for drop_in in drop_ins(path)? {
+            let mut layer = parse(&drop_in, &read(&drop_in)?)?;
+            migrate(&drop_in, &mut layer, version)?;
+            merge(&mut value, layer.clone());
+            layers.push((Source::File(drop_in), layer));
+        }
+
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+        for (var, raw) in vars {
+            if HOOK_VARS.contains(&var.as_str()) {
+                continue;
+            }
+            let key = var[ENV_PREFIX.len()..].to_lowercase();
+            if !ENV_SETTINGS.contains(&key.as_str()) {
+                return Err(Error::invalid(format!(
+                    "unknown environment variable {}, the settings that can be overridden are {}",
+                    var,
+                    ENV_SETTINGS
+                        .iter()
+                        .map(|key| format!("{}{}", ENV_PREFIX, key.to_uppercase()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+            let mut settings = toml::map::Map::new();
+            settings.insert(key, env_value(&raw));
+            let mut layer = toml::map::Map::new();
+            layer.insert(String::from("settings"), Value::Table(settings));
+            merge(&mut value, Value::Table(layer.clone()));
+            layers.push((Source::Var(var), Value::Table(layer)));
+        }
+
+        Ok(Layers {
+            path: path.to_path_buf(),
+            value,
+            text: (layers.len() == 1).then_some(text),
+            layers,
+        })
+    }
+
+    /// The merged document.
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Parses the merged document. Errors are attributed to the first
+    /// layer that breaks the config when merged over the ones before it,
+    /// and only to the main file as a whole if none does.
+    pub fn config(&self) -> Result<Config, Error> {
+        let parsed = match &self.text {
+            Some(text) => Config::parse(text),
+            None => self.value.clone().try_into(),
+        };
+        parsed.map_err(|source| {
+            self.blame().unwrap_or_else(|| Error::ParseConfig {
+                path: self.path.clone(),
+                source,
+            })
+        })
+    }
+
+    // Merges the layers one by one over the keys every config needs, so a
+    // drop-in can rely on others to set those, and returns the error of the
+    // first one that doesn't parse
+    fn blame(&self) -> Option<Error> {
+        if self.text.is_some() {
+            return None;
+        }
+        let mut value: Value = toml::from_str(REQUIRED).unwrap();
+        for (source, layer) in &self.layers {
+            merge(&mut value, layer.clone());
+            if let Err(err) = value.clone().try_into::<Config>() {
+                return Some(source.error(err));
+            }
+        }
+        None
+    }
+}
+
+// Placeholders for the keys a config can't do without
+const REQUIRED: &str = "[settings]\nupdate_rate = 1.0\n[fan_curve]\nraw_curve = []\n";
+
+fn read(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|source| Error::ReadConfig {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn parse(path: &Path, text: &str) -> Result<Value, Error> {
+    toml::from_str(text).map_err(|source| Error::ParseConfig {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Renames the old layout's keys in every layer, before they are merged
+fn migrate(path: &Path, value: &mut Value, version: i64) -> Result<(), Error> {
+    config::migrate(value, version)
+        .map(|_| ())
+        .map_err(|message| Error::ParseConfig {
+            path: path.to_path_buf(),
+            source: de::Error::custom(message),
+        })
+}
+
+/// The drop-ins for the config file at `path`, in the order they apply.
+pub fn drop_ins(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = path.with_extension("d");
+    let read_error = |source| Error::ReadConfig {
+        path: dir.clone(),
+        source,
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(read_error(err)),
+    };
+
+    let mut drop_ins = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(read_error)?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            drop_ins.push(path);
+        }
+    }
+    drop_ins.sort();
+    Ok(drop_ins)
+}
+
+/// Merges `layer` into `base`, recursing into tables present in both.
+pub fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Table(base), Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+// Environment variables hold TOML values, except that plain strings don't
+// need quotes
+fn env_value(raw: &str) -> Value {
+    toml::from_str::<toml::map::Map<String, Value>>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Layers;
+    use crate::sensor::Aggregation;
+    use crate::testing::scratch_dir;
+    use std::fs;
+
+    #[test]
+    fn drop_ins_and_environment() {
+        let dir = scratch_dir("layers");
+        let path = dir.join("pi-fan.toml");
+        fs::write(
+            &path,
+            "[settings]\nupdate_rate = 1.0\nlog_interval = 30\n\
+             [fan_curve]\nraw_curve = [[40, 0], [70, 100]]\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.join("pi-fan.d")).unwrap();
+        fs::write(
+            dir.join("pi-fan.d/20-curve.toml"),
+            "[fan_curve]\nraw_curve = [[30, 0], [60, 100]]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("pi-fan.d/10-rate.toml"),
+            "[settings]\nupdate_rate = 5.0\n[fan_curve]\nraw_curve = []\n",
+        )
+        .unwrap();
+        fs::write(dir.join("pi-fan.d/README"), "not a drop-in").unwrap();
+
+        let vars = [
+            ("PI_FAN_UPDATE_RATE", "2.5"),
+            ("PI_FAN_AGGREGATION", "mean"),
+            ("PI_FAN_FAN", "set for the stall command"),
+            ("HOME", "/root"),
+        ]
+        .map(|(var, value)| (var.to_string(), value.to_string()));
+        let config = Layers::read(&path, vars).unwrap().config().unwrap();
+        assert_eq!(config.settings.update_rate, 2.5);
+        assert_eq!(config.settings.log_interval, 30.0);
+        assert_eq!(config.settings.aggregation, Aggregation::Mean);
+        assert_eq!(config.fan_curve.raw_curve, vec![(30.0, 0.0), (60.0, 100.0)]);
+
+        let vars = [(String::from("PI_FAN_UPDATE_RATE"), String::from("fast"))];
+        let err = Layers::read(&path, vars).unwrap().config().err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid config: PI_FAN_UPDATE_RATE: invalid type: string \"fast\""));
+
+        let vars = [(String::from("PI_FAN_UPDATERATE"), String::from("2.5"))];
+        let err = Layers::read(&path, vars).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid config: unknown environment variable PI_FAN_UPDATERATE"));
+
+        // Errors point at the drop-in that caused them
+        fs::write(
+            dir.join("pi-fan.d/15-fans.toml"),
+            "[[fans]]\nchannel = \"pwm2\"\n",
+        )
+        .unwrap();
+        let err = Layers::read(&path, []).unwrap().config().err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("15-fans.toml: unknown variant `pwm2`"));
+        fs::remove_file(dir.join("pi-fan.d/15-fans.toml")).unwrap();
+
+        // The old layout in the main file is renamed before the drop-ins
+        // are merged into it
+        fs::write(
+            &path,
+            "[settings]\nupdate_rate = 1.0\n[curve]\nfan-curve = [[40, 0], [70, 100]]\n",
+        )
+        .unwrap();
+        let config = Layers::read(&path, []).unwrap().config().unwrap();
+        assert_eq!(config.fan_curve.raw_curve, vec![(30.0, 0.0), (60.0, 100.0)]);
+
+        fs::write(
+            &path,
+            "version = 2\n[settings]\nupdate_rate = 1.0\n[fan_curve]\nraw_curve = []\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("pi-fan.d/30-old.toml"),
+            "[curve]\nfan-curve = [[40, 0], [70, 100]]\n",
+        )
+        .unwrap();
+        let err = Layers::read(&path, []).err().unwrap().to_string();
+        assert!(err.contains("30-old.toml"));
+        assert!(err.contains("only allowed in version 1 configs"));
+    }
+}
@@ -0,0 +1,27 @@
+//! Raspberry pi fan control.
+//!
+//! The `pi-fan` daemon only wires these modules together, so the curve,
+//! sensor and fan logic can be reused and tested on its own.
+
+pub mod check;
+pub mod config;
+pub mod control;
+pub mod controller;
+pub mod curve;
+pub mod error;
+pub mod fan;
+pub mod filter;
+pub mod layers;
+pub mod pid;
+pub mod plot;
+pub mod reload;
+pub mod sensor;
+pub mod sim;
+pub mod stall;
+pub mod tach;
+#[cfg(test)]
+mod testing;
+
+pub use config::Config;
+pub use curve::Curve;
+pub use error::Error;
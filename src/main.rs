@@ -1,183 +1,369 @@
-use rppal::pwm;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::{fs, thread, time};
-
-const FAIL_TEMP: i32 = -100;
-const FAIL_SPEED: f32 = 50.0;
-
-#[derive(Deserialize)]
-struct Config {
-    settings: Settings,
-    fan_curve: RawCurve,
+use clap::{Parser, Subcommand};
+use pi_fan::check;
+use pi_fan::config;
+use pi_fan::controller::{self, Controller, Hardware, RaspberryPi};
+use pi_fan::error::{EXIT_CONFIG, EXIT_STALLED, EXIT_USAGE};
+use pi_fan::layers::Layers;
+use pi_fan::plot::{self, Format};
+use pi_fan::reload::{self, ConfigWatcher};
+use pi_fan::sensor;
+use pi_fan::sim::{self, TraceSensor};
+use pi_fan::stall::{StallAction, StallEvent};
+use pi_fan::{Config, Error};
+use signal_hook::consts::SIGHUP;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{env, fs, io, process, thread, time};
+
+// Temperature range of the synthetic trace used by `--simulate` without a file
+const RAMP_FROM: i32 = 20;
+const RAMP_TO: i32 = 90;
+
+/// Raspberry pi fan control daemon
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file to read
+    #[arg(short, long, global = true, default_value = config::DEFAULT_PATH)]
+    config: PathBuf,
+    /// Log the state of every fan on every update
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Deserialize)]
-struct Settings {
-    update_rate: f32, // update rate in seconds
+#[derive(Subcommand)]
+enum Command {
+    /// Control the fans (the default)
+    Run {
+        /// Replay a trace of temperatures against a mock fan instead, or a
+        /// ramp from 20 to 90°C and back without TRACE
+        #[arg(long, value_name = "TRACE", num_args = 0..=1)]
+        simulate: Option<Option<PathBuf>>,
+    },
+    /// Check the config for problems, exiting with 1 if there are any
+    CheckConfig,
+    /// Print the state of every fan from the running daemon's `status_file`
+    Status,
+    /// Print a curve evaluated over a range of temperatures
+    Curve {
+        /// Curve in `curves` to show instead of `fan_curve`
+        name: Option<String>,
+        /// table, chart, csv or svg
+        #[arg(short, long, default_value = "table")]
+        format: Format,
+        /// Lowest temperature, defaults to a bit below the first point
+        #[arg(long)]
+        from: Option<f32>,
+        /// Highest temperature, defaults to a bit above the last point
+        #[arg(long)]
+        to: Option<f32>,
+        /// °C between samples
+        #[arg(long, default_value_t = 1.0)]
+        step: f32,
+        /// Write to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Inspect the effective config
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Hold fans at a fixed duty cycle until interrupted, to check wiring
+    Set {
+        /// Duty cycle in percent
+        speed: f32,
+        /// Name of a fan to set, defaults to all of them
+        #[arg(long)]
+        fan: Vec<String>,
+    },
 }
 
-#[derive(Deserialize)]
-struct RawCurve {
-    raw_curve: Vec<(i32, i32)>,
+impl Default for Command {
+    fn default() -> Self {
+        Command::Run { simulate: None }
+    }
 }
 
-struct Curve {
-    curve: HashMap<i32, i32>,
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the config file merged with its drop-ins and environment
+    /// variables
+    Dump,
 }
 
-impl From<Vec<(i32, i32)>> for Curve {
-    fn from(items: Vec<(i32, i32)>) -> Self {
-        let mut curve = HashMap::new();
-        for (temp, speed) in items.into_iter() {
-            curve.insert(temp, speed);
+fn main() {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or_default();
+    match command {
+        Command::Run { simulate: None } => run(&cli.config, cli.verbose),
+        Command::Run {
+            simulate: Some(trace),
+        } => simulate(&cli.config, trace),
+        Command::CheckConfig => check_config(&cli.config),
+        Command::Status => status(&cli.config),
+        Command::Curve {
+            name,
+            format,
+            from,
+            to,
+            step,
+            output,
+        } => preview_curve(&cli.config, name, format, (from, to, step), output),
+        Command::Config(ConfigCommand::Dump) => dump_config(&cli.config),
+        Command::Set { speed, fan } => {
+            if !(0.0..=100.0).contains(&speed) {
+                usage("the speed has to be between 0 and 100%");
+            }
+            set(&cli.config, speed, &fan)
         }
-        Curve { curve }
     }
 }
 
-impl Curve {
-    fn get_value_at(&self, temp: i32) -> f32 {
-        if self.curve.contains_key(&temp) {
-            *(self.curve.get(&temp).unwrap()) as f32
-        } else {
-            let mut keys: Vec<i32> = self.curve.keys().cloned().collect();
-            keys.sort();
-
-            let first = keys.first().unwrap();
-            let last = keys.last().unwrap();
-
-            if &temp <= first {
-                *(self.curve.get(first).unwrap()) as f32
-            } else if &temp >= last {
-                *(self.curve.get(first).unwrap()) as f32
-            } else {
-                let mut x1 = keys[0];
-                let mut x2 = keys[1];
-
-                for i in 2..keys.len() {
-                    if x1 <= temp && temp < x2 {
-                        break;
-                    } else {
-                        x1 = x2;
-                        x2 = keys[i];
-                    }
+fn run(config_path: &Path, verbose: bool) {
+    let mut config = Config::load(config_path).unwrap_or_else(|err| fail(err));
+
+    let available = sensor::discover(Path::new(sensor::SYSFS_CLASS));
+    println!("Found {} temperature sensors", available.len());
+    for info in available.iter() {
+        println!("  {}", info);
+    }
+
+    let mut controller = Controller::from_config(&config, &available, &mut RaspberryPi)
+        .unwrap_or_else(|err| fail(err));
+    for fan in controller.fans() {
+        println!("Controlling {}", fan.name());
+    }
+
+    let hangup = Arc::new(AtomicBool::new(false));
+    if let Err(err) = signal_hook::flag::register(SIGHUP, Arc::clone(&hangup)) {
+        eprintln!(
+            "Failed to listen for SIGHUP, reloading is disabled: {}",
+            err
+        );
+    }
+    let mut watcher = ConfigWatcher::new(config_path);
+
+    let mut last_log: Option<time::Instant> = None;
+    loop {
+        // Always poll so enabling `watch_config` doesn't trigger a reload
+        let changed = watcher.changed() && config.settings.watch_config;
+        if hangup.swap(false, Ordering::Relaxed) || changed {
+            match reload::reload(config_path, &mut controller) {
+                Ok(reloaded) => {
+                    config = reloaded;
+                    println!("Reloaded {}", config_path.display());
+                }
+                Err(err) => eprintln!("Keeping the previous config: {}", err),
+            }
+        }
+
+        let results = controller.update();
+        for (fan, result) in controller.fans().iter().zip(results) {
+            match result {
+                Ok(tick)
+                    if tick.stall == Some(StallEvent::Stalled)
+                        && fan.stall_action() == Some(StallAction::Exit) =>
+                {
+                    // Exiting skips dropping the outputs, so hardware PWM
+                    // fans are left running at full speed
+                    eprintln!("Exiting because {} is stalled", fan.name());
+                    process::exit(EXIT_STALLED);
                 }
+                Ok(_) => (),
+                Err(err) => eprintln!("Failed to set speed of {}: {}", fan.name(), err),
+            }
+        }
 
-                self.get_value_between_points(x1, x2, temp)
+        if let Some(status_file) = &config.settings.status_file {
+            if let Err(err) = controller.write_status(status_file) {
+                eprintln!("Failed to write {}: {}", status_file.display(), err);
             }
         }
+        let log_interval = config.settings.log_interval;
+        if verbose
+            || log_interval > 0.0
+                && last_log.is_none_or(|logged| logged.elapsed().as_secs_f32() >= log_interval)
+        {
+            print!("{}", controller.status());
+            last_log = Some(time::Instant::now());
+        }
+        thread::sleep(time::Duration::from_millis(
+            (config.settings.update_rate * 1000.0) as u64,
+        ));
     }
+}
 
-    fn get_value_between_points(&self, x1: i32, x2: i32, temp: i32) -> f32 {
-        let y1 = *(self.curve.get(&x1).unwrap());
-        let y2 = *(self.curve.get(&x2).unwrap());
-        let slope = (y2 - y1) as f32 / (x2 - x1) as f32;
-        let y = slope * (temp - x1) as f32 + y1 as f32;
-        y
+// Runs `fan_curve` against a trace and a mock fan, printing every tick
+fn simulate(config_path: &Path, trace_path: Option<PathBuf>) {
+    let config = Config::load(config_path).unwrap_or_else(|err| fail(err));
+    let curve = controller::named_curve(&config, None).unwrap_or_else(|err| fail(err));
+    let trace = match trace_path {
+        Some(path) => TraceSensor::from_csv(&path).unwrap_or_else(|err| {
+            eprintln!("Failed to read trace {}: {}", path.display(), err);
+            process::exit(EXIT_USAGE);
+        }),
+        None => TraceSensor::ramp(RAMP_FROM, RAMP_TO),
+    };
+    if let Err(err) = sim::run(trace, &curve, &mut io::stdout().lock()) {
+        eprintln!("Simulation failed: {}", err);
+        process::exit(1);
     }
 }
 
-fn get_temp() -> i32 {
-    fs::read_to_string("/sys/class/thermal/thermal_zone0/temp")
-        .expect("Failed to read temp")
-        .trim()
-        .parse::<i32>()
-        .unwrap_or(FAIL_TEMP) / 1000
+// Prints `err` and exits with the code for its kind of failure
+fn fail(err: Error) -> ! {
+    eprintln!("{}", err);
+    process::exit(err.exit_code());
 }
 
-fn get_speed(temp: i32, curve: &Curve) -> f32 {
-    if temp == FAIL_TEMP {
-        FAIL_SPEED
-    } else {
-        curve.get_value_at(temp)
-    }
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(EXIT_USAGE);
 }
 
-fn update_speed(pin: &pwm::Pwm, curve: &Curve) {
-    let temp = get_temp();
-    let speed = get_speed(temp, &curve);
+// Prints every problem with the config at `path` and exits, unsuccessfully
+// if there were any
+fn check_config(path: &Path) -> ! {
+    let config = Config::load(path).unwrap_or_else(|err| fail(err));
+    let problems = check::check_config(&config);
+    if problems.is_empty() {
+        println!("{} is valid", path.display());
+        process::exit(0);
+    }
+    for problem in problems.iter() {
+        eprintln!("{}: {}", path.display(), problem);
+    }
+    process::exit(EXIT_CONFIG);
+}
 
-    pin.set_duty_cycle((speed * 256.0) as f64).unwrap();
+// Prints the merged config after making sure it parses
+fn dump_config(config_path: &Path) {
+    let layers = Layers::read(config_path, env::vars()).unwrap_or_else(|err| fail(err));
+    layers.config().unwrap_or_else(|err| fail(err));
+    match toml::to_string(layers.value()) {
+        Ok(text) => print!("{}", text),
+        Err(err) => {
+            eprintln!("Failed to write config: {}", err);
+            process::exit(1);
+        }
+    }
 }
 
-fn main() {
-    let config_path = if cfg!(debug_assertions) {
-        String::from("res/config.toml")
-    } else {
-        String::from("/etc/pi-fan.toml")
+fn status(config_path: &Path) {
+    let config = Config::load(config_path).unwrap_or_else(|err| fail(err));
+    let status_file = match &config.settings.status_file {
+        Some(status_file) => status_file,
+        None => fail(Error::invalid(
+            "`settings.status_file` has to be set to get the status",
+        )),
     };
+    match fs::read_to_string(status_file) {
+        Ok(status) => print!("{}", status),
+        Err(err) => {
+            eprintln!(
+                "Failed to read {}, is pi-fan running? {}",
+                status_file.display(),
+                err
+            );
+            process::exit(1);
+        }
+    }
+}
 
-    let config_file = std::fs::read_to_string(config_path).unwrap();
-    let config: Config = toml::from_str(config_file.as_str()).unwrap();
-    let curve: Curve = Curve::from(config.fan_curve.raw_curve);
+// Writes the curve called `name`, or `fan_curve`, evaluated over a range of
+// temperatures
+fn preview_curve(
+    config_path: &Path,
+    name: Option<String>,
+    format: Format,
+    (from, to, step): (Option<f32>, Option<f32>, f32),
+    output: Option<PathBuf>,
+) {
+    let config = Config::load(config_path).unwrap_or_else(|err| fail(err));
+    let curve = controller::named_curve(&config, name.as_deref()).unwrap_or_else(|err| fail(err));
+    let (default_from, default_to) = plot::default_range(&curve);
+    let (from, to) = (from.unwrap_or(default_from), to.unwrap_or(default_to));
+    if let Err(err) = plot::check_range(from, to, step) {
+        usage(&err);
+    }
+    let samples = plot::sample(&curve, from, to, step);
 
-    let pwm_pin = pwm::Pwm::with_frequency(
-        pwm::Channel::Pwm0,
-        25000.0,
-        0.0,
-        pwm::Polarity::Normal,
-        true,
-    )
-    .unwrap();
+    let result = match &output {
+        Some(path) => fs::File::create(path)
+            .and_then(|mut file| plot::write(format, &curve, &samples, &mut file)),
+        None => plot::write(format, &curve, &samples, &mut io::stdout().lock()),
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to write curve: {}", err);
+        process::exit(1);
+    }
+}
 
+// Sets the fans called `names`, or all of them, to `speed` and waits to be
+// interrupted. The outputs aren't dropped then, so hardware PWM channels and
+// switch pins keep their state until something else sets them. Software PWM
+// stops with the process, leaving its pin at whatever level it was at.
+fn set(config_path: &Path, speed: f32, names: &[String]) {
+    let config = Config::load(config_path).unwrap_or_else(|err| fail(err));
+    let fan_configs = config.fan_configs();
+    let fans: Vec<String> = (0..fan_configs.len())
+        .map(|i| fan_configs[i].name(i))
+        .collect();
+    if let Some(unknown) = names.iter().find(|name| !fans.contains(name)) {
+        fail(Error::invalid(format!("unknown fan {:?}", unknown)));
+    }
+
+    let mut outputs = Vec::new();
+    for (fan_config, name) in fan_configs.iter().zip(fans) {
+        if !names.is_empty() && !names.contains(&name) {
+            continue;
+        }
+        let mut output = RaspberryPi.open_fan(fan_config).unwrap_or_else(|source| {
+            fail(Error::Fan {
+                name: name.clone(),
+                kind: fan_config.kind,
+                source,
+            })
+        });
+        if let Err(err) = output.set_speed(speed) {
+            eprintln!("Failed to set speed of {}: {}", name, err);
+            process::exit(1);
+        }
+        println!("Holding {} at {:.1}%, press Ctrl-C to stop", name, speed);
+        outputs.push(output);
+    }
     loop {
-        update_speed(&pwm_pin, &curve);
-        thread::sleep(time::Duration::from_millis(
-            (config.settings.update_rate * 1000.0) as u64,
-        ));
+        thread::park();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn basic_speed() {
-        let curve = vec![
-            (0, 0),
-            (10, 100),
-            (20, 200),
-            (30, 300),
-            (40, 400),
-            (50, 500)
-        ];
-        let curve = super::Curve::from(curve);
-        assert_eq!(curve.get_value_at(0), 0.0);
-        assert_eq!(curve.get_value_at(10), 100.0);
-        assert_eq!(curve.get_value_at(20), 200.0);
-        assert_eq!(curve.get_value_at(30), 300.0);
-        assert_eq!(curve.get_value_at(40), 400.0);
-        assert_eq!(curve.get_value_at(50), 500.0);
-    }
+    use super::{Cli, Command};
+    use clap::{CommandFactory, Parser};
+    use std::path::PathBuf;
 
     #[test]
-    fn linear_speed() {
-        let curve = vec![
-            (0, 0),
-            (10, 100),
-            (20, 200),
-            (30, 300),
-            (40, 400),
-            (50, 500),
-        ];
-        let curve = super::Curve::from(curve);
-        assert_eq!(curve.get_value_at(5), 50.0);
-        assert_eq!(curve.get_value_at(15), 150.0);
-        assert_eq!(curve.get_value_at(25), 250.0);
-        assert_eq!(curve.get_value_at(35), 350.0);
-        assert_eq!(curve.get_value_at(45), 450.0);
-    }
-    #[test]
-    fn quadratic_speed() {
-        let curve = vec![
-            (0, 0),
-            (10, 100),
-            (20, 300),
-            (30, 700)
-        ];
-        let curve = super::Curve::from(curve);
-        assert_eq!(curve.get_value_at(5), 50.0);
-        assert_eq!(curve.get_value_at(15), 200.0);
-        assert_eq!(curve.get_value_at(25), 500.0);
+    fn command_line() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["pi-fan"]).unwrap();
+        assert_eq!(cli.config, PathBuf::from(pi_fan::config::DEFAULT_PATH));
+        assert!(matches!(
+            cli.command.unwrap_or_default(),
+            Command::Run { simulate: None }
+        ));
+
+        let cli = Cli::try_parse_from(["pi-fan", "run", "--simulate", "-c", "test.toml"]).unwrap();
+        assert_eq!(cli.config, PathBuf::from("test.toml"));
+        assert!(matches!(
+            cli.command,
+            Some(Command::Run {
+                simulate: Some(None)
+            })
+        ));
+        assert!(Cli::try_parse_from(["pi-fan", "set"]).is_err());
     }
 }
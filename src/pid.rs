@@ -0,0 +1,120 @@
+// Default gains for RPM targets: %/rpm and %/(rpm·s)
+pub const RPM_KP: f32 = 0.01;
+pub const RPM_KI: f32 = 0.02;
+
+// Default gains for temperature setpoints: %/°C, %/(°C·s) and %·s/°C
+pub const TEMP_KP: f32 = 5.0;
+pub const TEMP_KI: f32 = 0.1;
+pub const TEMP_KD: f32 = 2.0;
+
+/// A PID controller with its output clamped to a range. The integral stops
+/// accumulating while the output is saturated, so it doesn't wind up, and
+/// the derivative can be low-pass filtered to keep sensor noise from making
+/// the output jumpy.
+pub struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    min_output: f32,
+    max_output: f32,
+    derivative_filter: f32, // time constant in seconds
+    integral: f32,          // in output units, so changing `ki` doesn't make it jump
+    derivative: f32,
+    last_error: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(kp: f32, ki: f32, kd: f32, min_output: f32, max_output: f32) -> Self {
+        Pid {
+            kp,
+            ki,
+            kd,
+            min_output,
+            max_output,
+            derivative_filter: 0.0,
+            integral: 0.0,
+            derivative: 0.0,
+            last_error: None,
+        }
+    }
+
+    /// Smooths the derivative with a first order low-pass filter with time
+    /// constant `seconds`.
+    pub fn with_derivative_filter(mut self, seconds: f32) -> Self {
+        self.derivative_filter = seconds.max(0.0);
+        self
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.derivative = 0.0;
+        self.last_error = None;
+    }
+
+    /// Returns the output for `error` (setpoint minus measurement), `dt`
+    /// seconds after the previous update.
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let derivative = match self.last_error {
+            Some(last_error) if dt > 0.0 => (error - last_error) / dt,
+            _ => 0.0,
+        };
+        let alpha = if self.derivative_filter > 0.0 {
+            dt / (self.derivative_filter + dt)
+        } else {
+            1.0
+        };
+        self.derivative += alpha * (derivative - self.derivative);
+        let derivative = self.derivative;
+        self.last_error = Some(error);
+
+        let proportional = self.kp * error;
+        let integral = self.integral + self.ki * error * dt;
+        let output = proportional + integral + self.kd * derivative;
+
+        // Only integrate when that doesn't push further into saturation
+        let saturated_high = output > self.max_output && error > 0.0;
+        let saturated_low = output < self.min_output && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral = integral.clamp(self.min_output, self.max_output);
+        }
+
+        (proportional + self.integral + self.kd * derivative)
+            .clamp(self.min_output, self.max_output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pid;
+
+    #[test]
+    fn integrates_to_steady_state() {
+        let mut pid = Pid::new(0.0, 1.0, 0.0, 0.0, 100.0);
+        assert_eq!(pid.update(10.0, 1.0), 10.0);
+        assert_eq!(pid.update(10.0, 1.0), 20.0);
+        assert_eq!(pid.update(0.0, 1.0), 20.0);
+        assert_eq!(pid.update(-5.0, 2.0), 10.0);
+    }
+
+    #[test]
+    fn anti_windup() {
+        let mut pid = Pid::new(1.0, 1.0, 0.0, 0.0, 100.0);
+        for _ in 0..100 {
+            assert_eq!(pid.update(200.0, 1.0), 100.0);
+        }
+        // A wound up integral would keep the output pinned for a while
+        assert!(pid.update(-20.0, 1.0) < 100.0);
+    }
+
+    #[test]
+    fn filtered_derivative() {
+        let mut pid = Pid::new(0.0, 0.0, 1.0, -100.0, 100.0);
+        pid.update(0.0, 1.0);
+        assert_eq!(pid.update(10.0, 1.0), 10.0);
+
+        let mut pid = Pid::new(0.0, 0.0, 1.0, -100.0, 100.0).with_derivative_filter(1.0);
+        pid.update(0.0, 1.0);
+        assert_eq!(pid.update(10.0, 1.0), 5.0);
+        assert_eq!(pid.update(10.0, 1.0), 2.5);
+    }
+}
@@ -0,0 +1,278 @@
+//! Previews of a curve, evaluated with the same `Curve::get_value_at` the
+//! daemon uses, so what is plotted is what the fan will do.
+
+use crate::curve::Curve;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+pub const CHART_HEIGHT: usize = 20; // rows between 0% and the top of the chart
+pub const MAX_SAMPLES: usize = 10000; // more than any terminal or plot can show
+
+const SVG_WIDTH: f32 = 640.0;
+const SVG_HEIGHT: f32 = 320.0;
+const SVG_MARGIN: f32 = 40.0;
+
+/// How a preview is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Table,
+    Chart, // ASCII chart for the terminal
+    Csv,
+    Svg,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "chart" => Ok(Format::Chart),
+            "csv" => Ok(Format::Csv),
+            "svg" => Ok(Format::Svg),
+            _ => Err(format!(
+                "unknown format {:?}, expected table, chart, csv or svg",
+                s
+            )),
+        }
+    }
+}
+
+/// Makes sure sampling from `from` to `to`°C in steps of `step` gives a
+/// sensible number of samples.
+pub fn check_range(from: f32, to: f32, step: f32) -> Result<(), String> {
+    if step.is_nan() || step <= 0.0 {
+        return Err(String::from("the step has to be positive"));
+    }
+    if from.is_nan() || to.is_nan() || from >= to {
+        return Err(format!("{}°C to {}°C isn't an increasing range", from, to));
+    }
+    let count = sample_count(from, to, step);
+    if count > MAX_SAMPLES as f32 {
+        return Err(format!(
+            "a step of {}°C from {}°C to {}°C gives {} samples, at most {} are allowed",
+            step, from, to, count, MAX_SAMPLES
+        ));
+    }
+    Ok(())
+}
+
+fn sample_count(from: f32, to: f32, step: f32) -> f32 {
+    ((to - from) / step).floor().max(0.0) + 1.0
+}
+
+/// Evaluates `curve` from `from` to `to`°C in steps of `step`, which should
+/// have passed `check_range`.
+pub fn sample(curve: &Curve, from: f32, to: f32, step: f32) -> Vec<(f32, f32)> {
+    let count = sample_count(from, to, step).min(MAX_SAMPLES as f32) as usize;
+    (0..count)
+        .map(|i| from + i as f32 * step)
+        .map(|temp| (temp, curve.get_value_at(temp)))
+        .collect()
+}
+
+/// The range `sample` covers by default: the curve's points with some room
+/// on either side, on whole multiples of 5°C.
+pub fn default_range(curve: &Curve) -> (f32, f32) {
+    let points = curve.points();
+    let first = points[0].0;
+    let last = points[points.len() - 1].0;
+    (
+        ((first - 5.0) / 5.0).floor() * 5.0,
+        ((last + 5.0) / 5.0).ceil() * 5.0,
+    )
+}
+
+pub fn write<W: Write>(
+    format: Format,
+    curve: &Curve,
+    samples: &[(f32, f32)],
+    out: &mut W,
+) -> io::Result<()> {
+    match format {
+        Format::Table => table(samples, out),
+        Format::Chart => chart(samples, out),
+        Format::Csv => csv(samples, out),
+        Format::Svg => svg(curve, samples, out),
+    }
+}
+
+pub fn table<W: Write>(samples: &[(f32, f32)], out: &mut W) -> io::Result<()> {
+    writeln!(out, "temp\tspeed")?;
+    for (temp, speed) in samples {
+        writeln!(out, "{:.1}\t{:.1}", temp, speed)?;
+    }
+    Ok(())
+}
+
+pub fn csv<W: Write>(samples: &[(f32, f32)], out: &mut W) -> io::Result<()> {
+    writeln!(out, "temp,speed")?;
+    for (temp, speed) in samples {
+        writeln!(out, "{},{}", temp, speed)?;
+    }
+    Ok(())
+}
+
+// Highest speed on the vertical axis, 100 unless the curve goes past it as
+// RPM curves do
+fn top(samples: &[(f32, f32)]) -> f32 {
+    samples.iter().map(|sample| sample.1).fold(100.0, f32::max)
+}
+
+/// One column per sample, with the speed axis on the left and a temperature
+/// label under every tenth column.
+pub fn chart<W: Write>(samples: &[(f32, f32)], out: &mut W) -> io::Result<()> {
+    let top = top(samples);
+    let rows: Vec<usize> = samples
+        .iter()
+        .map(|sample| (sample.1.max(0.0) / top * CHART_HEIGHT as f32).round() as usize)
+        .collect();
+
+    for row in (0..=CHART_HEIGHT).rev() {
+        let label = if row % 5 == 0 {
+            format!("{:.0}", top * row as f32 / CHART_HEIGHT as f32)
+        } else {
+            String::new()
+        };
+        let line: String = rows
+            .iter()
+            .map(|&height| if height == row { '*' } else { ' ' })
+            .collect();
+        writeln!(out, "{:>6} |{}", label, line.trim_end())?;
+    }
+
+    writeln!(out, "{:>6} +{}", "", "-".repeat(samples.len()))?;
+    let mut labels = String::new();
+    for (i, (temp, _)) in samples.iter().enumerate().step_by(10) {
+        let label = format!("{:.0}", temp);
+        labels.push_str(&" ".repeat(i.saturating_sub(labels.len())));
+        labels.push_str(&label);
+    }
+    writeln!(out, "{:>6}  {}", "°C", labels)
+}
+
+/// A standalone SVG with the sampled curve as a line and its points marked.
+pub fn svg<W: Write>(curve: &Curve, samples: &[(f32, f32)], out: &mut W) -> io::Result<()> {
+    let (from, to) = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) if last.0 > first.0 => (first.0, last.0),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "nothing to plot",
+            ))
+        }
+    };
+    let top = top(samples);
+    let x = |temp: f32| SVG_MARGIN + (temp - from) / (to - from) * (SVG_WIDTH - 2.0 * SVG_MARGIN);
+    let y = |speed: f32| SVG_HEIGHT - SVG_MARGIN - speed / top * (SVG_HEIGHT - 2.0 * SVG_MARGIN);
+
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = SVG_WIDTH,
+        h = SVG_HEIGHT
+    )?;
+    writeln!(
+        out,
+        r#"<path d="M{x0:.1},{y0:.1} V{y1:.1} M{x0:.1},{y0:.1} H{x1:.1}" stroke="black" fill="none"/>"#,
+        x0 = x(from),
+        x1 = x(to),
+        y0 = y(0.0),
+        y1 = y(top)
+    )?;
+    for speed in [0.0, top / 2.0, top] {
+        writeln!(
+            out,
+            r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{:.0}</text>"#,
+            x(from) - 6.0,
+            y(speed) + 4.0,
+            speed
+        )?;
+    }
+    for temp in [from, (from + to) / 2.0, to] {
+        writeln!(
+            out,
+            r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{:.0}°C</text>"#,
+            x(temp),
+            y(0.0) + 18.0,
+            temp
+        )?;
+    }
+
+    let line: Vec<String> = samples
+        .iter()
+        .map(|&(temp, speed)| format!("{:.1},{:.1}", x(temp), y(speed)))
+        .collect();
+    writeln!(
+        out,
+        r#"<polyline points="{}" stroke="steelblue" stroke-width="2" fill="none"/>"#,
+        line.join(" ")
+    )?;
+    for &(temp, speed) in curve.points() {
+        if (from..=to).contains(&temp) {
+            writeln!(
+                out,
+                r#"<circle cx="{:.1}" cy="{:.1}" r="3" fill="steelblue"/>"#,
+                x(temp),
+                y(speed)
+            )?;
+        }
+    }
+    writeln!(out, "</svg>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chart, check_range, default_range, sample, svg, Format};
+    use crate::curve::Curve;
+
+    #[test]
+    fn samples() {
+        let curve = Curve::new(vec![(40.0, 0.0), (60.0, 100.0)]).unwrap();
+        assert_eq!(default_range(&curve), (35.0, 65.0));
+        assert_eq!(
+            sample(&curve, 30.0, 70.0, 10.0),
+            vec![
+                (30.0, 0.0),
+                (40.0, 0.0),
+                (50.0, 50.0),
+                (60.0, 100.0),
+                (70.0, 100.0)
+            ]
+        );
+        assert_eq!(check_range(30.0, 70.0, 0.5), Ok(()));
+        assert!(check_range(80.0, 20.0, 1.0).is_err());
+        assert!(check_range(20.0, 80.0, 0.0).is_err());
+        assert!(check_range(0.0, 100.0, 0.0001).is_err());
+
+        assert_eq!("svg".parse(), Ok(Format::Svg));
+        assert!("png".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn plots() {
+        let curve = Curve::new(vec![(40.0, 0.0), (60.0, 100.0)]).unwrap();
+        let samples = sample(&curve, 30.0, 70.0, 1.0);
+
+        let mut out = Vec::new();
+        chart(&samples, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "   100 |                              ***********"
+        );
+        assert_eq!(lines[20], "     0 |***********");
+        assert_eq!(
+            lines[22],
+            "    °C  30        40        50        60        70"
+        );
+
+        let mut out = Vec::new();
+        svg(&curve, &samples, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("<svg"));
+        assert_eq!(out.matches("<circle").count(), 2);
+    }
+}
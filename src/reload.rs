@@ -0,0 +1,90 @@
+//! Picking up config changes while the daemon runs, on SIGHUP or when the
+//! config files change, without releasing the fans in between.
+
+use crate::config::Config;
+use crate::controller::Controller;
+use crate::error::Error;
+use crate::layers;
+use crate::sensor;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Loads the config at `path` and switches `controller` over to it,
+/// returning the new config. On error `controller` is left as it was.
+pub fn reload(path: &Path, controller: &mut Controller) -> Result<Config, Error> {
+    let config = Config::load(path)?;
+    let available = sensor::discover(Path::new(sensor::SYSFS_CLASS));
+    controller.reload(&config, &available)?;
+    Ok(config)
+}
+
+/// Notices changes to a config file and its drop-ins by polling their
+/// modification times.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    stamps: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl ConfigWatcher {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        ConfigWatcher {
+            stamps: stamps(&path),
+            path,
+        }
+    }
+
+    /// Whether any of the files changed, appeared or disappeared since the
+    /// last call.
+    pub fn changed(&mut self) -> bool {
+        let stamps = stamps(&self.path);
+        let changed = stamps != self.stamps;
+        self.stamps = stamps;
+        changed
+    }
+}
+
+fn stamps(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files = vec![path.to_path_buf()];
+    // An unreadable drop-in directory is reported when reloading
+    files.extend(layers::drop_ins(path).unwrap_or_default());
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = fs::metadata(&file).and_then(|metadata| metadata.modified());
+            (file, modified.ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigWatcher;
+    use crate::testing::scratch_dir;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn watches_drop_ins() {
+        let dir = scratch_dir("reload");
+        fs::create_dir(dir.join("pi-fan.d")).unwrap();
+        let path = dir.join("pi-fan.toml");
+        fs::write(&path, "").unwrap();
+
+        let mut watcher = ConfigWatcher::new(&path);
+        assert!(!watcher.changed());
+        fs::write(dir.join("pi-fan.d/10-curve.toml"), "").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        // Set the time explicitly, writes in quick succession may not
+        // change it on coarse grained filesystems
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(watcher.changed());
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.changed());
+    }
+}
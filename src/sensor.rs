@@ -0,0 +1,388 @@
+use crate::config::SensorConfig;
+use crate::curve::Curve;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+pub const SYSFS_CLASS: &str = "/sys/class";
+pub const THERMAL_ZONE0: &str = "/sys/class/thermal/thermal_zone0/temp";
+
+/// Anything that can report a temperature in °C.
+pub trait TemperatureSource {
+    fn read_temp(&mut self) -> io::Result<f32>;
+}
+
+impl<T: TemperatureSource + ?Sized> TemperatureSource for Box<T> {
+    fn read_temp(&mut self) -> io::Result<f32> {
+        (**self).read_temp()
+    }
+}
+
+/// A sysfs temperature file reporting millidegrees, as exposed by thermal
+/// zones and hwmon devices.
+pub struct SysfsSensor {
+    path: PathBuf,
+}
+
+impl SysfsSensor {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        SysfsSensor { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Default for SysfsSensor {
+    fn default() -> Self {
+        SysfsSensor::new(THERMAL_ZONE0)
+    }
+}
+
+impl TemperatureSource for SysfsSensor {
+    fn read_temp(&mut self) -> io::Result<f32> {
+        fs::read_to_string(&self.path)?
+            .trim()
+            .parse::<i32>()
+            .map(|millis| millis as f32 / 1000.0)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// How the readings of several sensors are combined into the control input.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Aggregation {
+    #[default]
+    Max, // hottest sensor
+    Mean,
+    Weighted, // mean using each sensor's `weight`
+    MaxCurve, // run every sensor through the curve and use the highest speed
+}
+
+struct Member {
+    name: String,
+    sensor: Box<dyn TemperatureSource>,
+    weight: f32,
+    curve: Option<Curve>,
+    failing: bool, // the last read failed, so the next failure isn't logged again
+}
+
+/// Several sensors read together and combined according to an
+/// `Aggregation`. Sensors that fail are skipped as long as at least one can
+/// be read.
+///
+/// Sensors can bring their own curve, in which case they are left out of the
+/// aggregation and the caller evaluates them on their own.
+pub struct SensorGroup {
+    members: Vec<Member>,
+    aggregation: Aggregation,
+}
+
+impl SensorGroup {
+    pub fn new(aggregation: Aggregation) -> Self {
+        SensorGroup {
+            members: Vec::new(),
+            aggregation,
+        }
+    }
+
+    /// A group holding just `sensor`.
+    pub fn single<S: TemperatureSource + 'static>(sensor: S) -> Self {
+        let mut group = SensorGroup::new(Aggregation::Max);
+        group.add("sensor", sensor, 1.0);
+        group
+    }
+
+    pub fn add<S: TemperatureSource + 'static>(&mut self, name: &str, sensor: S, weight: f32) {
+        self.members.push(Member {
+            name: name.to_string(),
+            sensor: Box::new(sensor),
+            weight,
+            curve: None,
+            failing: false,
+        });
+    }
+
+    /// Adds a sensor that is evaluated against its own `curve`.
+    pub fn add_with_curve<S: TemperatureSource + 'static>(
+        &mut self,
+        name: &str,
+        sensor: S,
+        curve: Curve,
+    ) {
+        self.members.push(Member {
+            name: name.to_string(),
+            sensor: Box::new(sensor),
+            weight: 1.0,
+            curve: Some(curve),
+            failing: false,
+        });
+    }
+
+    /// The curve of each sensor, in the order they were added.
+    pub fn curves(&self) -> impl Iterator<Item = Option<&Curve>> {
+        self.members.iter().map(|member| member.curve.as_ref())
+    }
+
+    pub fn aggregation(&self) -> Aggregation {
+        self.aggregation
+    }
+
+    /// Reads every sensor once, in the order they were added. Failed reads
+    /// are reported as `None`, and logged when a sensor starts or stops
+    /// failing rather than on every read.
+    pub fn read(&mut self) -> Vec<Option<f32>> {
+        self.members
+            .iter_mut()
+            .map(|member| {
+                let reading = member.sensor.read_temp();
+                match &reading {
+                    Err(err) if !member.failing => {
+                        eprintln!("Failed to read {}: {}", member.name, err)
+                    }
+                    Ok(_) if member.failing => eprintln!("{} can be read again", member.name),
+                    _ => (),
+                }
+                member.failing = reading.is_err();
+                reading.ok()
+            })
+            .collect()
+    }
+
+    /// Combines readings from `read` of the sensors without their own curve
+    /// into a single temperature. `MaxCurve` reports the hottest reading
+    /// here; the curve is applied by the caller.
+    pub fn combine(&self, readings: &[Option<f32>]) -> Option<f32> {
+        let valid = || {
+            self.members
+                .iter()
+                .zip(readings)
+                .filter(|(member, _)| member.curve.is_none())
+                .filter_map(|(member, temp)| temp.map(|temp| (member.weight, temp)))
+        };
+        valid().next()?;
+
+        let temp = match self.aggregation {
+            Aggregation::Max | Aggregation::MaxCurve => {
+                valid().map(|(_, temp)| temp).fold(f32::MIN, f32::max)
+            }
+            Aggregation::Mean => {
+                valid().map(|(_, temp)| temp).sum::<f32>() / valid().count() as f32
+            }
+            Aggregation::Weighted => {
+                let total: f32 = valid().map(|(weight, _)| weight).sum();
+                if total <= 0.0 {
+                    return None;
+                }
+                valid().map(|(weight, temp)| weight * temp).sum::<f32>() / total
+            }
+        };
+        Some(temp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    ThermalZone,
+    Hwmon,
+}
+
+/// A temperature input found under sysfs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SensorInfo {
+    pub kind: SensorKind,
+    pub name: String,          // thermal zone `type` or hwmon `name`
+    pub input: Option<String>, // hwmon input file, e.g. "temp1_input"
+    pub path: PathBuf,
+}
+
+impl fmt::Display for SensorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.kind, &self.input) {
+            (SensorKind::ThermalZone, _) => write!(f, "thermal_zone {:?}", self.name)?,
+            (SensorKind::Hwmon, Some(input)) => write!(f, "hwmon {:?} {}", self.name, input)?,
+            (SensorKind::Hwmon, None) => write!(f, "hwmon {:?}", self.name)?,
+        }
+        write!(f, " ({})", self.path.display())
+    }
+}
+
+/// Lists the thermal zones and hwmon temperature inputs under `root`
+/// (normally `SYSFS_CLASS`), in directory order.
+pub fn discover(root: &Path) -> Vec<SensorInfo> {
+    let mut found = Vec::new();
+
+    for dir in sorted_entries(&root.join("thermal"), "thermal_zone") {
+        if let Ok(name) = fs::read_to_string(dir.join("type")) {
+            found.push(SensorInfo {
+                kind: SensorKind::ThermalZone,
+                name: name.trim().to_string(),
+                input: None,
+                path: dir.join("temp"),
+            });
+        }
+    }
+
+    for dir in sorted_entries(&root.join("hwmon"), "hwmon") {
+        let name = match fs::read_to_string(dir.join("name")) {
+            Ok(name) => name.trim().to_string(),
+            Err(_) => continue,
+        };
+        for input in sorted_entries(&dir, "temp") {
+            let file = input.file_name().unwrap().to_string_lossy().to_string();
+            if file.ends_with("_input") {
+                found.push(SensorInfo {
+                    kind: SensorKind::Hwmon,
+                    name: name.clone(),
+                    input: Some(file),
+                    path: input,
+                });
+            }
+        }
+    }
+
+    found
+}
+
+// Entries of `dir` whose file name starts with `prefix`, in natural order so
+// that thermal_zone10 sorts after thermal_zone9.
+fn sorted_entries(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(prefix))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort_by_key(|path| {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let digits = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+        let number: u32 = digits
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse()
+            .unwrap_or(0);
+        (number, name)
+    });
+    entries
+}
+
+/// Finds the sysfs file a sensor entry refers to among the `available`
+/// sensors returned by `discover`.
+pub fn resolve(config: &SensorConfig, available: &[SensorInfo]) -> io::Result<SysfsSensor> {
+    let not_found = |what: String| io::Error::new(io::ErrorKind::NotFound, what);
+
+    match (&config.path, &config.thermal_zone, &config.hwmon) {
+        (Some(path), None, None) => Ok(SysfsSensor::new(path)),
+        (None, Some(zone), None) => available
+            .iter()
+            .find(|info| info.kind == SensorKind::ThermalZone && &info.name == zone)
+            .map(|info| SysfsSensor::new(&info.path))
+            .ok_or_else(|| not_found(format!("no thermal zone of type {:?}", zone))),
+        (None, None, Some(hwmon)) => available
+            .iter()
+            .filter(|info| info.kind == SensorKind::Hwmon && &info.name == hwmon)
+            .find(|info| config.input.is_none() || info.input == config.input)
+            .map(|info| SysfsSensor::new(&info.path))
+            .ok_or_else(|| match &config.input {
+                Some(input) => not_found(format!("no hwmon {:?} input {:?}", hwmon, input)),
+                None => not_found(format!("no hwmon device named {:?}", hwmon)),
+            }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sensors need exactly one of `path`, `thermal_zone` or `hwmon`",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discover, resolve, Aggregation, SensorGroup, SysfsSensor, TemperatureSource};
+    use crate::config::SensorConfig;
+    use crate::sim::TraceSensor;
+    use crate::testing::scratch_dir;
+    use std::fs;
+
+    fn sensor_config() -> SensorConfig {
+        SensorConfig {
+            name: None,
+            path: None,
+            thermal_zone: None,
+            hwmon: None,
+            input: None,
+            weight: 1.0,
+            curve: None,
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn sysfs_millidegrees() {
+        let dir = scratch_dir("sensor");
+        let path = dir.join("temp");
+        fs::write(&path, "48312\n").unwrap();
+        let mut sensor = SysfsSensor::new(&path);
+        assert_eq!(sensor.read_temp().unwrap(), 48.312);
+
+        fs::write(&path, "garbage\n").unwrap();
+        assert!(sensor.read_temp().is_err());
+    }
+
+    #[test]
+    fn discover_and_resolve() {
+        let root = scratch_dir("sysfs");
+        for (zone, name) in [("thermal_zone0", "cpu-thermal"), ("thermal_zone1", "pmic")] {
+            let dir = root.join("thermal").join(zone);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("type"), format!("{}\n", name)).unwrap();
+        }
+        let nvme = root.join("hwmon").join("hwmon2");
+        fs::create_dir_all(&nvme).unwrap();
+        fs::write(nvme.join("name"), "nvme\n").unwrap();
+        fs::write(nvme.join("temp1_input"), "41000\n").unwrap();
+        fs::write(nvme.join("temp2_input"), "39000\n").unwrap();
+        fs::write(nvme.join("temp1_label"), "Composite\n").unwrap();
+
+        let found = discover(&root);
+        assert_eq!(found.len(), 4);
+
+        let mut config = sensor_config();
+        config.thermal_zone = Some(String::from("pmic"));
+        let sensor = resolve(&config, &found).unwrap();
+        assert_eq!(sensor.path(), root.join("thermal/thermal_zone1/temp"));
+
+        let mut config = sensor_config();
+        config.hwmon = Some(String::from("nvme"));
+        assert_eq!(resolve(&config, &found).unwrap().read_temp().unwrap(), 41.0);
+        config.input = Some(String::from("temp2_input"));
+        assert_eq!(resolve(&config, &found).unwrap().read_temp().unwrap(), 39.0);
+
+        config.thermal_zone = Some(String::from("pmic"));
+        assert!(resolve(&config, &found).is_err());
+    }
+
+    #[test]
+    fn aggregation() {
+        let group = |aggregation| {
+            let mut group = SensorGroup::new(aggregation);
+            group.add("soc", TraceSensor::new(vec![60.0]), 3.0);
+            group.add("missing", TraceSensor::new(vec![]), 1.0);
+            group.add("nvme", TraceSensor::new(vec![40.0]), 1.0);
+            group
+        };
+
+        let mut max = group(Aggregation::Max);
+        let readings = max.read();
+        assert_eq!(readings, vec![Some(60.0), None, Some(40.0)]);
+        assert_eq!(max.combine(&readings), Some(60.0));
+        assert_eq!(group(Aggregation::Mean).combine(&readings), Some(50.0));
+        assert_eq!(group(Aggregation::Weighted).combine(&readings), Some(55.0));
+        assert_eq!(max.combine(&[None, None, None]), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Dry runs of the control loop without any fan hardware attached.
+
+use crate::control::{self, Tick};
+use crate::curve::Curve;
+use crate::fan::FanOutput;
+use crate::sensor::{SensorGroup, TemperatureSource};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::{fs, path::Path};
+
+/// Replays a scripted list of temperatures, one per read.
+pub struct TraceSensor {
+    temps: VecDeque<f32>,
+}
+
+impl TraceSensor {
+    pub fn new(temps: Vec<f32>) -> Self {
+        TraceSensor {
+            temps: temps.into(),
+        }
+    }
+
+    /// A synthetic trace climbing from `from` to `to` in 1°C steps and back.
+    pub fn ramp(from: i32, to: i32) -> Self {
+        let up = from..=to;
+        let down = (from..to).rev();
+        TraceSensor::new(up.chain(down).map(|temp| temp as f32).collect())
+    }
+
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        TraceSensor::parse_csv(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a trace with one sample per line, taking the temperature from
+    /// the last column so both `temp` and `time,temp` files work. Blank
+    /// lines, `#` comments and a header line are skipped.
+    pub fn parse_csv(text: &str) -> io::Result<Self> {
+        let mut temps = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let field = line.rsplit(',').next().unwrap_or(line).trim();
+            match field.parse::<f32>() {
+                Ok(temp) => temps.push(temp),
+                Err(_) if temps.is_empty() => continue,
+                Err(err) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "line {}: invalid temperature {:?}: {}",
+                            number + 1,
+                            field,
+                            err
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(TraceSensor::new(temps))
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.temps.len()
+    }
+}
+
+impl TemperatureSource for TraceSensor {
+    fn read_temp(&mut self) -> io::Result<f32> {
+        self.temps
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "end of trace"))
+    }
+}
+
+/// A stand-in for the PWM channel that logs every duty cycle it is given.
+#[derive(Default)]
+pub struct MockFan {
+    history: Vec<f32>,
+}
+
+impl MockFan {
+    pub fn history(&self) -> &[f32] {
+        &self.history
+    }
+}
+
+impl FanOutput for MockFan {
+    fn set_speed(&mut self, speed: f32) -> io::Result<()> {
+        self.history.push(speed);
+        Ok(())
+    }
+}
+
+/// Runs the control loop over the whole trace, writing a `tick temp duty`
+/// table to `out`.
+pub fn run<W: Write>(trace: TraceSensor, curve: &Curve, out: &mut W) -> io::Result<MockFan> {
+    let ticks = trace.remaining();
+    let mut sensors = SensorGroup::single(trace);
+    let mut fan = MockFan::default();
+
+    writeln!(out, "tick\ttemp\tduty")?;
+    for tick in 0..ticks {
+        let Tick { temp, speed, .. } = control::update_speed(&mut sensors, &mut fan, curve)?;
+        let temp = temp.map_or_else(|| String::from("-"), |temp| format!("{:.1}", temp));
+        writeln!(out, "{}\t{}\t{:.1}", tick, temp, speed)?;
+    }
+    Ok(fan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, TraceSensor};
+    use crate::curve::Curve;
+
+    #[test]
+    fn parse_trace() {
+        let trace = "time,temp\n0,40.2\n\n# spike\n1,61.7\n2,55\n";
+        let trace = TraceSensor::parse_csv(trace).unwrap();
+        assert_eq!(trace.temps, vec![40.2, 61.7, 55.0]);
+
+        assert!(TraceSensor::parse_csv("40\nhot\n").is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let curve = Curve::new(vec![(40.0, 0.0), (60.0, 100.0)]).unwrap();
+        let trace = TraceSensor::ramp(45, 47);
+        let mut out = Vec::new();
+        let fan = run(trace, &curve, &mut out).unwrap();
+
+        assert_eq!(fan.history(), &[25.0, 30.0, 35.0, 30.0, 25.0]);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().nth(3), Some("2\t47.0\t35.0"));
+    }
+}
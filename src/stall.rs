@@ -0,0 +1,239 @@
+//! Detecting fans that stopped spinning from their tachometer, and what to do
+//! about it.
+
+use crate::config::StallConfig;
+use crate::control::Tick;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{fs, io, thread};
+
+pub const KICK_SPEED: f32 = 100.0;
+pub const CPUFREQ: &str = "/sys/devices/system/cpu/cpufreq";
+
+// A fan below this fraction of the RPM expected for its duty counts as stalled
+const EXPECTED_RPM_RATIO: f32 = 0.25;
+
+/// What to do once a fan stays stalled after being kick-started.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum StallAction {
+    #[default]
+    Log,
+    Exit,     // stop the daemon so the service manager notices
+    Throttle, // cap every CPU at its lowest frequency until the fan recovers
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallEvent {
+    KickStart, // the fan looked stalled and is being run at full speed
+    Stalled,   // it didn't recover from the kick-start
+    Recovered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Running,
+    Stalling(f32), // seconds spent stalled so far
+    Kicking(f32),  // seconds of kick-start left
+    Stalled,
+}
+
+/// Watches a fan's RPM against the duty it was given. A fan that stays too
+/// slow for `grace` seconds is kick-started at full speed for `kick`
+/// seconds, and reported as stalled if that doesn't help.
+pub struct StallDetector {
+    min_rpm: f32,
+    max_rpm: Option<f32>,
+    min_duty: f32,
+    grace: f32,
+    kick: f32,
+    state: State,
+    last_duty: f32,
+}
+
+impl StallDetector {
+    pub fn new(config: &StallConfig) -> Self {
+        StallDetector {
+            min_rpm: config.min_rpm,
+            max_rpm: config.max_rpm,
+            min_duty: config.min_duty,
+            grace: config.grace,
+            kick: config.kick,
+            state: State::Running,
+            last_duty: 0.0,
+        }
+    }
+
+    /// Picks up where `other` left off, so a fan that is being kick-started
+    /// or is stalled isn't taken to be running again.
+    pub fn resume(&mut self, other: &StallDetector) {
+        self.state = other.state;
+        self.last_duty = other.last_duty;
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.state == State::Stalled
+    }
+
+    // Whether `rpm`, measured while the fan ran at `last_duty`, is too slow
+    fn too_slow(&self, rpm: f32) -> bool {
+        if self.last_duty <= self.min_duty {
+            return false;
+        }
+        let expected = self
+            .max_rpm
+            .map_or(0.0, |max_rpm| max_rpm * self.last_duty / 100.0);
+        rpm < self.min_rpm || rpm < expected * EXPECTED_RPM_RATIO
+    }
+
+    /// Takes the `duty` the fan should run at and the `rpm` measured since
+    /// the last update, `dt` seconds ago. Returns the duty to actually use
+    /// and what changed, if anything.
+    pub fn update(&mut self, duty: f32, rpm: Option<f32>, dt: f32) -> (f32, Option<StallEvent>) {
+        // Without a reading there is nothing to judge
+        let too_slow = rpm.is_some_and(|rpm| self.too_slow(rpm));
+
+        let (state, event) = match self.state {
+            State::Running | State::Stalling(_) if !too_slow => (State::Running, None),
+            State::Running | State::Stalling(_) => {
+                let stalled_for = match self.state {
+                    State::Stalling(seconds) => seconds + dt,
+                    _ => dt,
+                };
+                if stalled_for >= self.grace {
+                    (State::Kicking(self.kick), Some(StallEvent::KickStart))
+                } else {
+                    (State::Stalling(stalled_for), None)
+                }
+            }
+            State::Kicking(left) if left > dt => (State::Kicking(left - dt), None),
+            State::Kicking(_) if too_slow => (State::Stalled, Some(StallEvent::Stalled)),
+            State::Kicking(_) => (State::Running, None),
+            State::Stalled if too_slow => (State::Stalled, None),
+            State::Stalled => (State::Running, Some(StallEvent::Recovered)),
+        };
+        self.state = state;
+
+        // Keep a stalled fan at full speed in case it comes back
+        self.last_duty = match state {
+            State::Kicking(_) | State::Stalled => KICK_SPEED,
+            _ => duty,
+        };
+        (self.last_duty, event)
+    }
+}
+
+/// Runs an alarm hook through `sh -c` without waiting for it. The fan's name,
+/// duty and RPM are passed in `PI_FAN_FAN`, `PI_FAN_DUTY` and `PI_FAN_RPM`.
+pub fn run_hook(command: &str, fan: &str, tick: &Tick) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PI_FAN_FAN", fan)
+        .env("PI_FAN_DUTY", format!("{:.1}", tick.speed))
+        .env("PI_FAN_RPM", format!("{:.0}", tick.rpm.unwrap_or(0.0)))
+        .spawn()?;
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Caps every cpufreq policy under `root` (normally `CPUFREQ`) at its
+/// lowest frequency, returning the limits it replaced for `restore_cpu`.
+pub fn throttle_cpu(root: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+    let mut saved = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let policy = entry?.path();
+        if !policy.join("cpuinfo_min_freq").exists() {
+            continue;
+        }
+        let max_freq = policy.join("scaling_max_freq");
+        let min_freq = fs::read_to_string(policy.join("cpuinfo_min_freq"))?;
+        saved.push((max_freq.clone(), fs::read_to_string(&max_freq)?));
+        fs::write(max_freq, min_freq.trim())?;
+    }
+    Ok(saved)
+}
+
+/// Puts back the limits `throttle_cpu` replaced.
+pub fn restore_cpu(saved: &[(PathBuf, String)]) -> io::Result<()> {
+    for (path, max_freq) in saved {
+        fs::write(path, max_freq.trim())?;
+    }
+    Ok(())
+}
+
+pub fn shutdown() -> io::Result<()> {
+    Command::new("shutdown").args(["-h", "now"]).spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restore_cpu, throttle_cpu, StallDetector, StallEvent};
+    use crate::config::StallConfig;
+    use crate::testing::scratch_dir;
+    use std::fs;
+
+    fn new_detector() -> StallDetector {
+        let config: StallConfig = toml::from_str("min_rpm = 300\ngrace = 2\nkick = 2").unwrap();
+        StallDetector::new(&config)
+    }
+
+    #[test]
+    fn kick_start_then_stalled() {
+        let mut detector = new_detector();
+        let updates: Vec<_> = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+            .into_iter()
+            .map(|rpm| detector.update(40.0, Some(rpm), 1.0))
+            .collect();
+        assert_eq!(
+            updates,
+            vec![
+                (40.0, None),
+                (40.0, None),
+                (100.0, Some(StallEvent::KickStart)),
+                (100.0, None),
+                (100.0, Some(StallEvent::Stalled)),
+                (100.0, None),
+            ]
+        );
+        assert!(detector.is_stalled());
+        assert_eq!(
+            detector.update(40.0, Some(2000.0), 1.0),
+            (40.0, Some(StallEvent::Recovered))
+        );
+    }
+
+    #[test]
+    fn kick_start_recovers() {
+        let mut detector = new_detector();
+        for rpm in [1000.0, 0.0, 0.0, 0.0] {
+            detector.update(40.0, Some(rpm), 1.0);
+        }
+        assert_eq!(detector.update(40.0, Some(1500.0), 1.0), (40.0, None));
+        assert!(!detector.is_stalled());
+
+        // A fan that is allowed to stop isn't stalled
+        let mut detector = new_detector();
+        for _ in 0..5 {
+            assert_eq!(detector.update(0.0, Some(0.0), 1.0), (0.0, None));
+        }
+    }
+
+    #[test]
+    fn throttle_and_restore() {
+        let root = scratch_dir("cpufreq");
+        let policy = root.join("policy0");
+        fs::create_dir_all(&policy).unwrap();
+        fs::write(policy.join("cpuinfo_min_freq"), "600000\n").unwrap();
+        fs::write(policy.join("scaling_max_freq"), "1800000\n").unwrap();
+        let max_freq = || fs::read_to_string(policy.join("scaling_max_freq")).unwrap();
+
+        let saved = throttle_cpu(&root).unwrap();
+        assert_eq!(max_freq(), "600000");
+        restore_cpu(&saved).unwrap();
+        assert_eq!(max_freq(), "1800000");
+    }
+}
@@ -0,0 +1,93 @@
+//! Fan speed feedback from the tachometer wire of 4-pin fans.
+
+use crate::config::TachConfig;
+use rppal::gpio::{self, Gpio, InputPin, Trigger};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const PULSES_PER_REVOLUTION: u32 = 2; // as specified for 4-pin PC fans
+
+/// Anything that can report how fast a fan is spinning.
+pub trait RpmSource {
+    fn read_rpm(&mut self) -> io::Result<f32>;
+}
+
+/// Counts tachometer pulses on a GPIO pin with an interrupt and reports the
+/// average RPM since the previous read.
+pub struct Tachometer {
+    _pin: InputPin, // clears the interrupt when dropped
+    pulses: Arc<AtomicU64>,
+    pulses_per_revolution: u32,
+    last_count: u64,
+    last_read: Instant,
+}
+
+impl Tachometer {
+    pub fn new(pin: u8, pulses_per_revolution: u32) -> gpio::Result<Self> {
+        // The tach output is open collector, so it needs the pull-up
+        let mut pin = Gpio::new()?.get(pin)?.into_input_pullup();
+        let pulses = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&pulses);
+        pin.set_async_interrupt(Trigger::FallingEdge, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })?;
+
+        Ok(Tachometer {
+            _pin: pin,
+            pulses,
+            pulses_per_revolution,
+            last_count: 0,
+            last_read: Instant::now(),
+        })
+    }
+}
+
+impl RpmSource for Tachometer {
+    fn read_rpm(&mut self) -> io::Result<f32> {
+        let count = self.pulses.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let rpm = rpm(
+            count - self.last_count,
+            now - self.last_read,
+            self.pulses_per_revolution,
+        );
+        self.last_count = count;
+        self.last_read = now;
+        Ok(rpm)
+    }
+}
+
+/// Converts a number of tach pulses counted over `elapsed` into RPM.
+pub fn rpm(pulses: u64, elapsed: Duration, pulses_per_revolution: u32) -> f32 {
+    let seconds = elapsed.as_secs_f32();
+    if seconds <= 0.0 || pulses_per_revolution == 0 {
+        return 0.0;
+    }
+    pulses as f32 / pulses_per_revolution as f32 / seconds * 60.0
+}
+
+/// Opens the tachometer described by a fan's `tach` config.
+pub fn open(config: &TachConfig) -> io::Result<Box<dyn RpmSource>> {
+    let tach =
+        Tachometer::new(config.pin, config.pulses_per_revolution).map_err(|err| match err {
+            gpio::Error::Io(err) => err,
+            err => io::Error::other(err),
+        })?;
+    Ok(Box::new(tach))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rpm;
+    use std::time::Duration;
+
+    #[test]
+    fn pulses_to_rpm() {
+        assert_eq!(rpm(100, Duration::from_secs(1), 2), 3000.0);
+        assert_eq!(rpm(25, Duration::from_millis(500), 1), 3000.0);
+        assert_eq!(rpm(0, Duration::from_secs(1), 2), 0.0);
+        assert_eq!(rpm(10, Duration::ZERO, 2), 0.0);
+    }
+}
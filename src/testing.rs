@@ -0,0 +1,31 @@
+//! Helpers shared by the unit tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// An empty directory for a test's files, removed again when dropped.
+pub struct ScratchDir(PathBuf);
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Creates a scratch directory; `name` has to be unique among the tests,
+/// since they run in parallel.
+pub fn scratch_dir(name: &str) -> ScratchDir {
+    let dir = env::temp_dir().join(format!("pi-fan-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    ScratchDir(dir)
+}